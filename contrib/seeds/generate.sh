@@ -0,0 +1,21 @@
+#!/bin/sh
+# Regenerate a fixed seed list from a running seeder's dnsseed.dump:
+#
+#   contrib/seeds/generate.sh /var/lib/bitcoin-seed/dnsseed.dump > contrib/seeds/nodes_main.txt
+#
+# Keeps up to 512 good nodes that were up more than half of the last 30 days,
+# most reliable first.
+set -e
+
+dump=${1:?usage: $0 <dnsseed.dump>}
+
+nodes=$(awk '!/^#/ && $2 == 1 && $8 + 0 > 50 { print $1 }' "$dump" | head -n 512)
+# an empty list would leave the fallback with nothing to visit
+if [ -z "$nodes" ]; then
+    echo "no good nodes in $dump, is the seeder crawling?" >&2
+    exit 1
+fi
+
+echo "# Fixed seeds, used when bootstrapping from DNS fails."
+echo "# Generated by contrib/seeds/generate.sh on $(date -u +%Y-%m-%d)."
+echo "$nodes"
@@ -0,0 +1,287 @@
+//! A Unix socket for inspecting and steering a running seeder, and the
+//! client the `bitcoin-seed admin` subcommand uses to talk to it.
+//!
+//! A client sends one command per line and gets one line of JSON back, an
+//! object with an `error` field if the command failed. `help` lists the
+//! commands.
+
+use log::{error, info, trace, LevelFilter};
+use serde_json::json;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use super::api;
+use super::ban::Rule;
+use super::crawler;
+use super::db;
+use super::metrics::Metrics;
+use super::utils;
+
+const HELP: &str = "stats | node <addr> | visit <addr> | bans \
+                    | ban <ip>[/<len>] [port=<port>] [until=<unix time>] \
+                    | allow <ip>[/<len>] [port=<port>] [until=<unix time>] \
+                    | unban <ip>[/<len>] [port=<port>] | snapshot \
+                    | log <off|error|warn|info|debug|trace|default>";
+// a client that goes quiet this long is disconnected, so it can't block
+// the next one
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+// how long `visit` waits for the node before answering anyway; the visit
+// carries on in the background and is recorded when it's done
+const VISIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+struct Context {
+    tdb: Arc<RwLock<db::NodeDb>>,
+    metrics: Arc<Metrics>,
+    dump_file: PathBuf,
+}
+
+// visit `addr` the way a worker would, on its own thread so a slow node
+// doesn't hold up other admin clients for longer than `VISIT_TIMEOUT`
+fn visit(context: &Context, addr: SocketAddr) -> Result<serde_json::Value, String> {
+    let node = {
+        let mut db = context.tdb.write().unwrap();
+        db.init(addr);
+        db.get(&addr).ok_or("address is banned")?
+    };
+    let (tdb, metrics) = (context.tdb.clone(), context.metrics.clone());
+    let (done, finished) = mpsc::channel();
+    thread::Builder::new()
+        .name(String::from("admin visit"))
+        .spawn(move || {
+            let result = crawler::visit(node.clone());
+            if let Err(ref e) = result {
+                info!("Visit to {} failed: {}", addr, e);
+            }
+            crawler::record(&tdb, &metrics, node, result);
+            let _ = done.send(());
+        })
+        .map_err(|e| e.to_string())?;
+    if finished.recv_timeout(VISIT_TIMEOUT).is_err() {
+        return Err(format!(
+            "still visiting {} after {}s, see `node {}` later",
+            addr,
+            VISIT_TIMEOUT.as_secs(),
+            addr
+        ));
+    }
+    let db = context.tdb.read().unwrap();
+    Ok(serde_json::to_value(api::node_detail(&db, &addr)).unwrap())
+}
+
+/// Bind the admin socket at `path`, replacing a stale one left behind by a
+/// seeder that's no longer running. Only our own user may connect.
+pub fn bind(path: &Path) -> Result<UnixListener, io::Error> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another seeder is listening there",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    // bound in a directory only we can enter and made private there before
+    // it's moved into place, so nobody can connect while it's still open to
+    // everyone; the umask is shared with threads already running
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".new");
+    let tmp = PathBuf::from(tmp);
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&tmp)?;
+    let bound = tmp.join("admin.sock");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    fs::remove_dir_all(&tmp)?;
+    listener
+}
+
+/// Answer admin commands on `listener` forever, one client at a time.
+/// `snapshot` writes the node dump to `dump_file`.
+pub fn serve(
+    listener: UnixListener,
+    tdb: Arc<RwLock<db::NodeDb>>,
+    metrics: Arc<Metrics>,
+    dump_file: PathBuf,
+) {
+    let context = Context {
+        tdb,
+        metrics,
+        dump_file,
+    };
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to accept admin connection: {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = serve_client(stream, &context) {
+            trace!("Admin connection closed: {:?}", e);
+        }
+    }
+}
+
+fn serve_client(stream: UnixStream, context: &Context) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        info!("Admin command: {}", line);
+        let response = match execute(context, line) {
+            Ok(value) => value,
+            Err(e) => json!({ "error": e }),
+        };
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+fn parse_addr(arg: Option<&str>) -> Result<SocketAddr, String> {
+    let arg = arg.ok_or("missing address")?;
+    arg.parse().map_err(|_| format!("bad address: {}", arg))
+}
+
+fn bans(db: &db::NodeDb) -> serde_json::Value {
+    let rules: Vec<String> = db.bans().rules().iter().map(|r| r.to_string()).collect();
+    json!({ "rules": rules })
+}
+
+fn execute(context: &Context, line: &str) -> Result<serde_json::Value, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or("");
+    let rest = line[command.len()..].trim();
+    match command {
+        "help" => Ok(json!({ "commands": HELP })),
+        "stats" => Ok(api::summary(&context.tdb.read().unwrap())),
+        "node" => {
+            let addr = parse_addr(words.next())?;
+            let db = context.tdb.read().unwrap();
+            let view = api::node_detail(&db, &addr).ok_or("unknown node")?;
+            Ok(serde_json::to_value(view).unwrap())
+        }
+        "visit" => visit(context, parse_addr(words.next())?),
+        "bans" => Ok(bans(&context.tdb.read().unwrap())),
+        "ban" | "allow" => {
+            let rule: Rule = line.parse().map_err(|e: io::Error| e.to_string())?;
+            let mut db = context.tdb.write().unwrap();
+            db.bans_mut().add(rule);
+            Ok(bans(&db))
+        }
+        "unban" => {
+            // the same syntax as a rule, less the expiry
+            let rule: Rule = format!("ban {}", rest)
+                .parse()
+                .map_err(|e: io::Error| e.to_string())?;
+            let mut db = context.tdb.write().unwrap();
+            if !db.bans_mut().remove(&rule.subnet, rule.port) {
+                return Err(format!("no rule for {}", rest));
+            }
+            Ok(bans(&db))
+        }
+        "snapshot" => {
+            crawler::save(&context.tdb, &context.dump_file).map_err(|e| e.to_string())?;
+            Ok(json!({ "saved": true }))
+        }
+        "log" => {
+            let level = match words.next() {
+                Some("default") => None,
+                Some(level) => Some(
+                    level
+                        .parse::<LevelFilter>()
+                        .map_err(|_| format!("bad level: {}", level))?,
+                ),
+                None => return Err(String::from("missing level")),
+            };
+            utils::set_log_level(level);
+            Ok(json!({ "level": level.map(|l| l.to_string()) }))
+        }
+        _ => Err(format!("unknown command, try: {}", HELP)),
+    }
+}
+
+/// Send `command` to the seeder listening at `path` and return its answer.
+pub fn request(path: &Path, command: &str) -> Result<serde_json::Value, io::Error> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        let dir = std::env::temp_dir().join(format!("seed-admin-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("admin.sock");
+        let listener = bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        assert!(!dir.join("admin.sock.new").exists());
+        let tdb = Arc::new(RwLock::new(db::NodeDb::new()));
+        tdb.write().unwrap().init("1.2.3.4:8333".parse().unwrap());
+        let dump_file = dir.join("dnsseed.dump");
+        let metrics = Arc::new(Metrics::new());
+        let (db, dump) = (tdb.clone(), dump_file.clone());
+        thread::spawn(move || serve(listener, db, metrics, dump));
+
+        let stats = request(&path, "stats").unwrap();
+        assert_eq!(1, stats["uncontacted"]);
+        let node = request(&path, "node 1.2.3.4:8333").unwrap();
+        assert_eq!("uncontacted", node["state"]);
+        assert!(request(&path, "node 5.6.7.8:8333").unwrap()["error"].is_string());
+
+        let bans = request(&path, "ban 10.0.0.0/8 port=8333").unwrap();
+        assert_eq!(json!(["ban 10.0.0.0/8 port=8333"]), bans["rules"]);
+        assert!(tdb
+            .read()
+            .unwrap()
+            .bans()
+            .is_banned(&"10.1.1.1:8333".parse().unwrap()));
+        // banned addresses can't be visited
+        assert!(request(&path, "visit 10.1.1.1:8333").unwrap()["error"].is_string());
+        let bans = request(&path, "unban 10.0.0.0/8 port=8333").unwrap();
+        assert_eq!(json!([]), bans["rules"]);
+        // nothing listening there, so the visit fails and is recorded at once
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let node = request(&path, &format!("visit {}", closed)).unwrap();
+        assert_eq!("offline", node["state"]);
+
+        assert_eq!(
+            json!({ "saved": true }),
+            request(&path, "snapshot").unwrap()
+        );
+        assert!(dump_file.exists());
+        assert!(request(&path, "frobnicate").unwrap()["error"].is_string());
+
+        // a second seeder can't take over the socket
+        assert!(bind(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
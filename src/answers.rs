@@ -0,0 +1,187 @@
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, RwLock};
+
+use super::db;
+
+// Ready-to-serve addresses for the seed name, rebuilt every few seconds from
+// the node table, so the DNS hot path never touches `NodeDb` (or waits
+// behind the crawler's writes). Readers grab an `Arc` to the current sets and
+// the refresh swaps in a new one.
+
+// service bits clients can ask for with an `x<hex>.` prefix, the same list
+// bitcoin-seeder precomputes. 0 is the bare seed name, any services.
+pub const SERVICE_FILTERS: [u64; 11] = [
+    0x0, 0x1, 0x5, 0x9, 0xd, 0x49, 0x4d, 0x400, 0x409, 0x449, 0x44d,
+];
+
+// the default answer sizes. Names aren't compressed, so with the seed name an
+// A record takes 35 bytes and an AAAA record 47: both defaults fit a 1232 byte
+// EDNS response, while plain 512 byte UDP responses get cut down to what fits
+// and marked truncated
+pub const MAX_A_RECORDS: usize = 25;
+pub const MAX_AAAA_RECORDS: usize = 16;
+
+#[derive(Debug, Default)]
+pub struct AnswerSets {
+    v4: HashMap<u64, Vec<Ipv4Addr>>,
+    v6: HashMap<u64, Vec<Ipv6Addr>>,
+}
+
+impl AnswerSets {
+    // good nodes on `port`, grouped by every filter their services satisfy
+    pub fn build(db: &db::NodeDb, port: u16) -> AnswerSets {
+        let mut sets = AnswerSets::default();
+        for filter in SERVICE_FILTERS.iter() {
+            sets.v4.insert(*filter, vec![]);
+            sets.v6.insert(*filter, vec![]);
+        }
+        for node in db.fetch_online_nodes(usize::max_value(), port) {
+            let services = node.version.as_ref().map_or(0, |v| v.services);
+            for filter in SERVICE_FILTERS.iter() {
+                if services & filter != *filter {
+                    continue;
+                }
+                match node.addr.ip() {
+                    IpAddr::V4(ip) => sets.v4.get_mut(filter).unwrap().push(ip),
+                    IpAddr::V6(ip) => sets.v6.get_mut(filter).unwrap().push(ip),
+                }
+            }
+        }
+        sets
+    }
+
+    // a random selection of up to `n`, so consecutive queries spread load
+    // across nodes
+    pub fn pick_v4(&self, filter: u64, n: usize) -> Vec<Ipv4Addr> {
+        pick(self.v4.get(&filter), n)
+    }
+
+    pub fn pick_v6(&self, filter: u64, n: usize) -> Vec<Ipv6Addr> {
+        pick(self.v6.get(&filter), n)
+    }
+}
+
+fn pick<T: Copy>(set: Option<&Vec<T>>, n: usize) -> Vec<T> {
+    match set {
+        Some(set) => set
+            .choose_multiple(&mut rand::thread_rng(), n)
+            .cloned()
+            .collect(),
+        None => vec![],
+    }
+}
+
+pub struct AnswerCache {
+    current: RwLock<Arc<AnswerSets>>,
+}
+
+impl AnswerCache {
+    pub fn new() -> AnswerCache {
+        AnswerCache {
+            current: RwLock::new(Arc::new(AnswerSets::default())),
+        }
+    }
+
+    pub fn get(&self) -> Arc<AnswerSets> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn refresh(&self, db: &db::NodeDb, port: u16) {
+        let sets = Arc::new(AnswerSets::build(db, port));
+        *self.current.write().unwrap() = sets;
+    }
+}
+
+// the service filter a query for `qname` asks for, if it's for our seed:
+// 0 for the bare name and the bits of an `x<hex>.` prefix otherwise
+pub fn service_filter(qname: &str, seed: &str) -> Option<u64> {
+    let qname = qname.to_lowercase();
+    if qname == seed {
+        return Some(0);
+    }
+    let suffix = format!(".{}", seed);
+    if !qname.ends_with(&suffix) {
+        return None;
+    }
+    let label = &qname[..qname.len() - suffix.len()];
+    if !label.starts_with('x') || label.contains('.') {
+        return None;
+    }
+    u64::from_str_radix(&label[1..], 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn node(addr: &str, services: u64) -> db::Node {
+        db::Node {
+            addr: addr.parse().unwrap(),
+            state: db::NodeState::Online,
+            last_visit: SystemTime::now(),
+            version: Some(db::VersionInfo {
+                version: 70015,
+                services,
+                user_agent: String::from("/Satoshi:0.18.0/"),
+                start_height: 600000,
+            }),
+            stats: db::NodeStats::default(),
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let mut db = db::NodeDb::new();
+        db.insert(node("1.1.1.1:8333", 0x409));
+        db.insert(node("2.2.2.2:8333", 0x1));
+        db.insert(node("[2001:db8::1]:8333", 0x9));
+        db.insert(node("3.3.3.3:8334", 0x409));
+
+        let cache = AnswerCache::new();
+        assert!(cache.get().pick_v4(0, MAX_A_RECORDS).is_empty());
+        cache.refresh(&db, 8333);
+        let sets = cache.get();
+
+        let mut all = sets.pick_v4(0, MAX_A_RECORDS);
+        all.sort();
+        assert_eq!(
+            vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(2, 2, 2, 2)],
+            all
+        );
+        assert_eq!(vec![Ipv4Addr::new(1, 1, 1, 1)], sets.pick_v4(0x9, MAX_A_RECORDS));
+        assert_eq!(
+            vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()],
+            sets.pick_v6(0x1, MAX_AAAA_RECORDS)
+        );
+        assert!(sets.pick_v6(0x400, MAX_AAAA_RECORDS).is_empty());
+        // not one of the precomputed filters
+        assert!(sets.pick_v4(0x2, MAX_A_RECORDS).is_empty());
+    }
+
+    #[test]
+    fn test_pick_limit() {
+        let mut db = db::NodeDb::new();
+        for i in 0..100 {
+            db.insert(node(&format!("10.0.0.{}:8333", i), 0x1));
+        }
+        let sets = AnswerSets::build(&db, 8333);
+        assert_eq!(MAX_A_RECORDS, sets.pick_v4(0, MAX_A_RECORDS).len());
+        assert_eq!(3, sets.pick_v4(0, 3).len());
+    }
+
+    #[test]
+    fn test_service_filter() {
+        let seed = "seed.example.com";
+        assert_eq!(Some(0), service_filter("seed.example.com", seed));
+        assert_eq!(Some(0), service_filter("Seed.Example.COM", seed));
+        assert_eq!(Some(0x9), service_filter("x9.seed.example.com", seed));
+        assert_eq!(Some(0x409), service_filter("x409.seed.example.com", seed));
+        assert_eq!(None, service_filter("xzz.seed.example.com", seed));
+        assert_eq!(None, service_filter("a.x9.seed.example.com", seed));
+        assert_eq!(None, service_filter("badseed.example.com", seed));
+        assert_eq!(None, service_filter("example.com", seed));
+    }
+}
@@ -0,0 +1,399 @@
+use bitcoin::network::constants::Network;
+use log::{trace, warn};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tiny_http::{Header, Method, Response, Server};
+
+use super::db;
+use super::metrics::Metrics;
+use super::utils;
+
+// Read-only JSON view of the node database:
+//
+//   GET /summary          counts per state, like the crawler's log line
+//   GET /nodes            paginated listing, see `NodeFilter` for the filters
+//   GET /nodes/<addr>     a single node, including who told us about it
+//   GET /metrics          Prometheus metrics (plain text, not JSON)
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Serialize)]
+pub struct NodeView {
+    addr: SocketAddr,
+    state: &'static str,
+    last_visit: u64,
+    version: Option<u32>,
+    services: Option<u64>,
+    user_agent: Option<String>,
+    height: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provenance: Option<ProvenanceView>,
+}
+
+#[derive(Serialize)]
+struct ProvenanceView {
+    source: SocketAddr,
+    first_seen: u64,
+    last_seen: u64,
+}
+
+impl NodeView {
+    fn new(node: &db::Node) -> NodeView {
+        let version = node.version.as_ref();
+        NodeView {
+            addr: node.addr,
+            state: state_name(&node.state),
+            last_visit: utils::unix_time(node.last_visit),
+            version: version.map(|v| v.version),
+            services: version.map(|v| v.services),
+            user_agent: version.map(|v| v.user_agent.clone()),
+            height: version.map(|v| v.start_height),
+            provenance: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NodeList {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    nodes: Vec<NodeView>,
+}
+
+fn state_name(state: &db::NodeState) -> &'static str {
+    match state {
+        db::NodeState::Online => "online",
+        db::NodeState::Offline => "offline",
+        db::NodeState::Uncontacted => "uncontacted",
+    }
+}
+
+fn parse_state(name: &str) -> Option<db::NodeState> {
+    match name {
+        "online" => Some(db::NodeState::Online),
+        "offline" => Some(db::NodeState::Offline),
+        "uncontacted" => Some(db::NodeState::Uncontacted),
+        _ => None,
+    }
+}
+
+// `/nodes` query parameters, every one given must match
+#[derive(Default, Debug)]
+struct NodeFilter {
+    // online, offline or uncontacted
+    state: Option<db::NodeState>,
+    // service bits the node must advertise, e.g. 1 for NODE_NETWORK
+    services: Option<u64>,
+    // ipv4 or ipv6
+    ipv6: Option<bool>,
+    // substring of the user agent, e.g. Satoshi:0.18
+    user_agent: Option<String>,
+    // minimum start height from the version message
+    min_height: Option<i32>,
+}
+
+impl NodeFilter {
+    fn from_query(params: &HashMap<String, String>) -> Result<NodeFilter, String> {
+        let mut filter = NodeFilter::default();
+        for (key, value) in params {
+            match key.as_str() {
+                "state" => {
+                    filter.state =
+                        Some(parse_state(value).ok_or(format!("unknown state: {}", value))?)
+                }
+                "services" => {
+                    filter.services =
+                        Some(value.parse().map_err(|_| format!("bad services: {}", value))?)
+                }
+                "network" => {
+                    filter.ipv6 = match value.as_str() {
+                        "ipv4" => Some(false),
+                        "ipv6" => Some(true),
+                        _ => return Err(format!("unknown network: {}", value)),
+                    }
+                }
+                "user_agent" => filter.user_agent = Some(value.clone()),
+                "min_height" => {
+                    filter.min_height =
+                        Some(value.parse().map_err(|_| format!("bad min_height: {}", value))?)
+                }
+                "offset" | "limit" => (),
+                _ => return Err(format!("unknown parameter: {}", key)),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, node: &db::Node) -> bool {
+        if let Some(ref state) = self.state {
+            if node.state != *state {
+                return false;
+            }
+        }
+        if let Some(ipv6) = self.ipv6 {
+            if node.addr.is_ipv6() != ipv6 {
+                return false;
+            }
+        }
+        let needs_version =
+            self.services.is_some() || self.user_agent.is_some() || self.min_height.is_some();
+        if !needs_version {
+            return true;
+        }
+        let version = match node.version {
+            Some(ref version) => version,
+            None => return false,
+        };
+        if let Some(services) = self.services {
+            if version.services & services != services {
+                return false;
+            }
+        }
+        if let Some(ref user_agent) = self.user_agent {
+            if !version.user_agent.contains(user_agent.as_str()) {
+                return false;
+            }
+        }
+        if let Some(min_height) = self.min_height {
+            if version.start_height < min_height {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// decode `a=1&b=%2FSatoshi%3A0.18.0%2F` into a map
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            let key = percent_decode(kv.next().unwrap());
+            let value = percent_decode(kv.next().unwrap_or(""));
+            (key, value)
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let hex = |b: u8| (b as char).to_digit(16);
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    out.push((hi * 16 + lo) as u8);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub fn summary(db: &db::NodeDb) -> serde_json::Value {
+    let report = db.report();
+    json!({
+        "online": report[&db::NodeState::Online],
+        "offline": report[&db::NodeState::Offline],
+        "uncontacted": report[&db::NodeState::Uncontacted],
+        "online_other_ports": db.count_online_on_other_ports(utils::default_port(Network::Bitcoin)),
+    })
+}
+
+fn list_nodes(db: &db::NodeDb, params: &HashMap<String, String>) -> Result<NodeList, String> {
+    let filter = NodeFilter::from_query(params)?;
+    let offset = match params.get("offset") {
+        Some(v) => v.parse().map_err(|_| format!("bad offset: {}", v))?,
+        None => 0,
+    };
+    let limit = match params.get("limit") {
+        Some(v) => v.parse().map_err(|_| format!("bad limit: {}", v))?,
+        None => DEFAULT_LIMIT,
+    };
+    let limit = std::cmp::min(limit, MAX_LIMIT);
+
+    // sort so pages are stable between requests
+    let mut nodes: Vec<db::Node> = db
+        .nodes()
+        .into_iter()
+        .filter(|node| filter.matches(node))
+        .collect();
+    nodes.sort_by_key(|node| node.addr);
+    Ok(NodeList {
+        total: nodes.len(),
+        offset,
+        limit,
+        nodes: nodes
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|node| NodeView::new(&node))
+            .collect(),
+    })
+}
+
+pub fn node_detail(db: &db::NodeDb, addr: &SocketAddr) -> Option<NodeView> {
+    let node = db.get(addr)?;
+    let mut view = NodeView::new(&node);
+    view.provenance = db.provenance(addr).map(|p| ProvenanceView {
+        source: p.source,
+        first_seen: utils::unix_time(p.first_seen),
+        last_seen: utils::unix_time(p.last_seen),
+    });
+    Some(view)
+}
+
+fn error(msg: &str) -> serde_json::Value {
+    json!({ "error": msg })
+}
+
+// turn a request into a status code and JSON body
+fn route(db: &db::NodeDb, method: &Method, url: &str) -> (u16, serde_json::Value) {
+    if *method != Method::Get {
+        return (405, error("method not allowed"));
+    }
+    let mut parts = url.splitn(2, '?');
+    let path = parts.next().unwrap();
+    let params = parse_query(parts.next().unwrap_or(""));
+
+    match path.trim_end_matches('/') {
+        "/summary" => (200, summary(db)),
+        "/nodes" => match list_nodes(db, &params) {
+            Ok(list) => (200, serde_json::to_value(list).unwrap()),
+            Err(e) => (400, error(&e)),
+        },
+        path if path.starts_with("/nodes/") => {
+            let addr = percent_decode(&path["/nodes/".len()..]);
+            match addr.parse::<SocketAddr>() {
+                Ok(addr) => match node_detail(db, &addr) {
+                    Some(view) => (200, serde_json::to_value(view).unwrap()),
+                    None => (404, error("unknown node")),
+                },
+                Err(_) => (400, error("bad address")),
+            }
+        }
+        _ => (404, error("not found")),
+    }
+}
+
+pub fn serve(tdb: Arc<RwLock<db::NodeDb>>, metrics: Arc<Metrics>, addr: SocketAddr) {
+    let server = match Server::http(addr) {
+        Ok(server) => server,
+        Err(e) => {
+            warn!("Couldn't start HTTP server on {}: {}", addr, e);
+            return;
+        }
+    };
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let text_type = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..]).unwrap();
+    for request in server.incoming_requests() {
+        trace!("HTTP {} {}", request.method(), request.url());
+        if *request.method() == Method::Get && request.url() == "/metrics" {
+            let body = metrics.render(&tdb.read().unwrap());
+            let response = Response::from_string(body).with_header(text_type.clone());
+            if let Err(e) = request.respond(response) {
+                trace!("Failed to send HTTP response: {}", e);
+            }
+            continue;
+        }
+        let (status, body) = {
+            let db = tdb.read().unwrap();
+            route(&db, request.method(), request.url())
+        };
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(content_type.clone());
+        if let Err(e) = request.respond(response) {
+            trace!("Failed to send HTTP response: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn node(addr: &str, state: db::NodeState, user_agent: &str, height: i32) -> db::Node {
+        db::Node {
+            addr: addr.parse().unwrap(),
+            state,
+            last_visit: SystemTime::now(),
+            version: Some(db::VersionInfo {
+                version: 70015,
+                services: 1 | 8 | 1024,
+                user_agent: String::from(user_agent),
+                start_height: height,
+            }),
+            stats: db::NodeStats::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("user_agent=%2FSatoshi%3A0.18.0%2F&state=online&x");
+        assert_eq!("/Satoshi:0.18.0/", params["user_agent"]);
+        assert_eq!("online", params["state"]);
+        assert_eq!("", params["x"]);
+        assert_eq!("100%", percent_decode("100%"));
+    }
+
+    #[test]
+    fn test_list_nodes() {
+        let mut db = db::NodeDb::new();
+        db.insert(node("1.1.1.1:8333", db::NodeState::Online, "/Satoshi:0.18.0/", 600000));
+        db.insert(node("2.2.2.2:8333", db::NodeState::Online, "/Satoshi:0.17.1/", 590000));
+        db.insert(node("[::1]:8333", db::NodeState::Offline, "/btcd:0.12.0/", 600000));
+        db.init("3.3.3.3:8333".parse().unwrap());
+
+        let list = |query| list_nodes(&db, &parse_query(query)).unwrap();
+        assert_eq!(4, list("").total);
+        assert_eq!(2, list("state=online").total);
+        assert_eq!(1, list("network=ipv6").total);
+        assert_eq!(1, list("user_agent=Satoshi%3A0.18").total);
+        assert_eq!(2, list("min_height=600000").total);
+        assert_eq!(3, list("services=1025").total);
+        assert_eq!(0, list("services=4").total);
+
+        let page = list("offset=1&limit=2");
+        assert_eq!(4, page.total);
+        assert_eq!(2, page.nodes.len());
+
+        assert!(list_nodes(&db, &parse_query("state=sleepy")).is_err());
+        assert!(list_nodes(&db, &parse_query("color=red")).is_err());
+    }
+
+    #[test]
+    fn test_route() {
+        let mut db = db::NodeDb::new();
+        db.init_from("1.1.1.1:8333".parse().unwrap(), "2.2.2.2:8333".parse().unwrap());
+
+        let (status, body) = route(&db, &Method::Get, "/summary");
+        assert_eq!(200, status);
+        assert_eq!(1, body["uncontacted"]);
+
+        let (status, body) = route(&db, &Method::Get, "/nodes/1.1.1.1:8333");
+        assert_eq!(200, status);
+        assert_eq!("uncontacted", body["state"]);
+        assert_eq!("2.2.2.2:8333", body["provenance"]["source"]);
+
+        assert_eq!(404, route(&db, &Method::Get, "/nodes/9.9.9.9:8333").0);
+        assert_eq!(400, route(&db, &Method::Get, "/nodes/nonsense").0);
+        assert_eq!(404, route(&db, &Method::Get, "/").0);
+        assert_eq!(405, route(&db, &Method::Post, "/summary").0);
+    }
+}
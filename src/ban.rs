@@ -0,0 +1,395 @@
+use log::{info, warn};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::utils;
+
+// The ban list is a plain text file, one rule per line:
+//
+//   # comments and blank lines are ignored
+//   ban 1.2.3.4
+//   ban 10.0.0.0/8 until=1735689600
+//   ban 2001:db8::/32 port=8333
+//   allow 10.1.2.3
+//
+// `allow` rules win over `ban` rules, so they can punch holes into banned
+// subnets. `until` is a unix timestamp after which the rule stops applying.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Ban,
+    Allow,
+}
+
+// an address range in CIDR notation, a bare IP is a /32 (or /128)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Subnet {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(32, self.prefix) as u32;
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(128, self.prefix);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// the top `prefix` bits of a `bits` wide integer
+fn mask(bits: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        return 0;
+    }
+    let all = if bits == 128 {
+        u128::max_value()
+    } else {
+        (1u128 << bits) - 1
+    };
+    all & !((1u128 << (bits - prefix)) - 1)
+}
+
+impl FromStr for Subnet {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Subnet, io::Error> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| invalid(format!("bad address: {}", s)))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| invalid(format!("bad prefix length: {}", s)))?,
+            None => max,
+        };
+        Ok(Subnet { addr, prefix })
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let max = if self.addr.is_ipv4() { 32 } else { 128 };
+        if self.prefix == max {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub subnet: Subnet,
+    // `None` matches every port
+    pub port: Option<u16>,
+    // `None` never expires
+    pub until: Option<SystemTime>,
+}
+
+impl Rule {
+    // fails if the expiry is too far out to represent
+    pub fn ban(
+        subnet: Subnet,
+        port: Option<u16>,
+        duration: Option<Duration>,
+    ) -> Result<Rule, io::Error> {
+        let until = match duration {
+            Some(d) => Some(
+                SystemTime::now()
+                    .checked_add(d)
+                    .ok_or_else(|| invalid(format!("ban duration too long: {:?}", d)))?,
+            ),
+            None => None,
+        };
+        Ok(Rule {
+            action: Action::Ban,
+            subnet,
+            port,
+            until,
+        })
+    }
+
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        self.subnet.contains(&addr.ip()) && self.port.map_or(true, |p| p == addr.port())
+    }
+
+    pub fn expired(&self, now: SystemTime) -> bool {
+        self.until.map_or(false, |until| until <= now)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = io::Error;
+
+    fn from_str(line: &str) -> Result<Rule, io::Error> {
+        let mut words = line.split_whitespace();
+        let action = match words.next() {
+            Some("ban") => Action::Ban,
+            Some("allow") => Action::Allow,
+            _ => return Err(invalid(format!("expected ban or allow: {}", line))),
+        };
+        let subnet: Subnet = words
+            .next()
+            .ok_or_else(|| invalid(format!("missing address: {}", line)))?
+            .parse()?;
+        let mut rule = Rule {
+            action,
+            subnet,
+            port: None,
+            until: None,
+        };
+        for word in words {
+            let mut kv = word.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("port"), Some(v)) => {
+                    rule.port = Some(v.parse().map_err(|_| invalid(format!("bad port: {}", line)))?)
+                }
+                (Some("until"), Some(v)) => {
+                    let secs: u64 = v
+                        .parse()
+                        .map_err(|_| invalid(format!("bad expiry: {}", line)))?;
+                    let until = UNIX_EPOCH
+                        .checked_add(Duration::from_secs(secs))
+                        .ok_or_else(|| invalid(format!("bad expiry: {}", line)))?;
+                    rule.until = Some(until);
+                }
+                _ => return Err(invalid(format!("unknown option {}: {}", word, line))),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            Action::Ban => "ban",
+            Action::Allow => "allow",
+        };
+        write!(f, "{} {}", action, self.subnet)?;
+        if let Some(port) = self.port {
+            write!(f, " port={}", port)?;
+        }
+        if let Some(until) = self.until {
+            let secs = until.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            write!(f, " until={}", secs)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub struct BanManager {
+    rules: Vec<Rule>,
+    // where the rules are persisted, `None` keeps them in memory only
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+impl BanManager {
+    pub fn new() -> BanManager {
+        BanManager {
+            rules: vec![],
+            path: None,
+            modified: None,
+        }
+    }
+
+    // load the rules in `path`, a missing file is an empty ban list
+    pub fn load(path: &Path) -> Result<BanManager, io::Error> {
+        let mut bans = BanManager::new();
+        bans.path = Some(path.to_path_buf());
+        bans.reload()?;
+        Ok(bans)
+    }
+
+    // re-read the ban file, the rules stay as they were if it doesn't parse
+    pub fn reload(&mut self) -> Result<(), io::Error> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut rules = vec![];
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            rules.push(line.parse()?);
+        }
+        self.rules = rules;
+        self.modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        Ok(())
+    }
+
+    // pick up edits made to the ban file since it was last read
+    pub fn reload_if_changed(&mut self) -> Result<bool, io::Error> {
+        let modified = match self.path {
+            Some(ref path) => fs::metadata(path).and_then(|m| m.modified()).ok(),
+            None => return Ok(false),
+        };
+        if modified == self.modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        info!("Reloaded ban list, {} rules", self.rules.len());
+        Ok(true)
+    }
+
+    // write the rules back to disk, replacing the old file atomically
+    pub fn save(&mut self) -> Result<(), io::Error> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let contents: String = self.rules.iter().map(|rule| format!("{}\n", rule)).collect();
+        utils::write_atomic(&path, &contents)?;
+        self.modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        Ok(())
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    // add a rule and persist the list
+    pub fn add(&mut self, rule: Rule) {
+        self.rules.push(rule);
+        self.persist();
+    }
+
+    // remove every rule for exactly this subnet and port, returns whether
+    // anything was removed
+    pub fn remove(&mut self, subnet: &Subnet, port: Option<u16>) -> bool {
+        let before = self.rules.len();
+        self.rules
+            .retain(|rule| !(rule.subnet == *subnet && rule.port == port));
+        let removed = self.rules.len() != before;
+        if removed {
+            self.persist();
+        }
+        removed
+    }
+
+    // drop rules whose expiry has passed
+    pub fn prune_expired(&mut self) {
+        let now = SystemTime::now();
+        let before = self.rules.len();
+        self.rules.retain(|rule| !rule.expired(now));
+        if self.rules.len() != before {
+            self.persist();
+        }
+    }
+
+    fn persist(&mut self) {
+        if let Err(e) = self.save() {
+            warn!("Couldn't save ban list: {}", e);
+        }
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        let now = SystemTime::now();
+        let mut banned = false;
+        for rule in self.rules.iter() {
+            if rule.expired(now) || !rule.matches(addr) {
+                continue;
+            }
+            match rule.action {
+                Action::Allow => return false,
+                Action::Ban => banned = true,
+            }
+        }
+        banned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rule() {
+        let rule: Rule = "ban 10.0.0.0/8 port=8333 until=1700000000".parse().unwrap();
+        assert_eq!(Action::Ban, rule.action);
+        assert_eq!(8, rule.subnet.prefix);
+        assert_eq!(Some(8333), rule.port);
+        assert_eq!(Some(UNIX_EPOCH + Duration::new(1700000000, 0)), rule.until);
+        assert_eq!("ban 10.0.0.0/8 port=8333 until=1700000000", rule.to_string());
+
+        assert!("ban 10.0.0.0/33".parse::<Rule>().is_err());
+        assert!("block 10.0.0.1".parse::<Rule>().is_err());
+        assert!("ban 10.0.0.1 for=10".parse::<Rule>().is_err());
+        // too far out for a SystemTime
+        let err = "ban 10.0.0.1 until=18446744073709551615"
+            .parse::<Rule>()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let subnet: Subnet = "10.0.0.1".parse().unwrap();
+        assert!(Rule::ban(subnet, None, Some(Duration::from_secs(u64::max_value()))).is_err());
+        assert!(Rule::ban(subnet, None, Some(Duration::from_secs(60))).is_ok());
+    }
+
+    #[test]
+    fn test_subnet_contains() {
+        let v4: Subnet = "192.168.0.0/16".parse().unwrap();
+        assert!(v4.contains(&"192.168.44.1".parse().unwrap()));
+        assert!(!v4.contains(&"192.169.0.1".parse().unwrap()));
+        assert!(!v4.contains(&"::1".parse().unwrap()));
+
+        let v6: Subnet = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains(&"2001:db9::1".parse().unwrap()));
+
+        let all: Subnet = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_is_banned() {
+        let mut bans = BanManager::new();
+        bans.add("ban 10.0.0.0/8".parse().unwrap());
+        bans.add("allow 10.0.0.1".parse().unwrap());
+        bans.add("ban 1.1.1.1 port=8333".parse().unwrap());
+        bans.add("ban 2.2.2.2 until=1".parse().unwrap());
+
+        assert!(bans.is_banned(&"10.1.2.3:8333".parse().unwrap()));
+        assert!(!bans.is_banned(&"10.0.0.1:8333".parse().unwrap()));
+        assert!(bans.is_banned(&"1.1.1.1:8333".parse().unwrap()));
+        assert!(!bans.is_banned(&"1.1.1.1:18333".parse().unwrap()));
+        // expired
+        assert!(!bans.is_banned(&"2.2.2.2:8333".parse().unwrap()));
+        bans.prune_expired();
+        assert_eq!(3, bans.rules().len());
+
+        assert!(bans.remove(&"10.0.0.0/8".parse().unwrap(), None));
+        assert!(!bans.is_banned(&"10.1.2.3:8333".parse().unwrap()));
+    }
+}
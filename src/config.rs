@@ -0,0 +1,309 @@
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use super::dns::{DnsConfig, MAX_RECORDS};
+use super::rrl::RrlConfig;
+
+// where the node table is kept
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    // in memory only, every restart starts from scratch
+    Memory,
+    // in memory, written to `nodes.json` in `data_dir` every minute (needs
+    // the `snapshot` feature)
+    Snapshot,
+    // in `nodes.sqlite` in `data_dir`, with a log of every visit (needs the
+    // `sqlite` feature)
+    Sqlite,
+}
+
+// Settings read from a TOML file passed with `--config`. Every field has a
+// default so an empty (or missing) file gives the same behavior as before.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // where the ban list, node dump and other state files live
+    pub data_dir: PathBuf,
+    // serve the JSON API here, disabled if unset
+    pub http_listen: Option<SocketAddr>,
+    // ask other operators' DNS seeds for addresses on startup
+    pub dns_bootstrap: bool,
+    // recursive resolvers used to look up the other DNS seeds
+    pub bootstrap_resolvers: Vec<SocketAddr>,
+    // bootstrap from a Bitcoin Core node's address files
+    pub peers_dat: Option<PathBuf>,
+    pub anchors_dat: Option<PathBuf>,
+    // bootstrap from a text file with one address per line
+    pub address_list: Option<PathBuf>,
+    // fall back to the compiled-in seeds if bootstrapping finds fewer addresses
+    pub min_seed_addresses: usize,
+    // bootstrap again whenever fewer nodes than this are online
+    pub min_online_nodes: usize,
+    // keep the node table in memory, a JSON snapshot or sqlite
+    pub store: StoreKind,
+    // drop logged visits older than this (sqlite store only)
+    pub visit_retention_days: u64,
+    // the DNS server answers over UDP and TCP on each of these
+    pub dns_listen: Vec<SocketAddr>,
+    // TTL and answer sizes, the `[dns]` table
+    pub dns: DnsConfig,
+    // DNS response rate limiting, the `[rrl]` table
+    pub rrl: RrlConfig,
+    // DNS cookies (RFC 7873), which exempt clients that echo them from `rrl`
+    pub dns_cookies: bool,
+    // run as this user (and group, the user's own by default) once the DNS
+    // sockets are bound
+    pub user: Option<String>,
+    pub group: Option<String>,
+    // confine ourselves to `data_dir` when dropping privileges
+    pub chroot: bool,
+    // take admin commands on `admin.sock` in `data_dir`, see `bitcoin-seed
+    // admin help`
+    pub admin_socket: bool,
+    // the file this was loaded from, re-read on SIGHUP; absolute, so it can
+    // be found again from inside a chroot
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            data_dir: PathBuf::from("."),
+            http_listen: None,
+            dns_bootstrap: true,
+            bootstrap_resolvers: vec![
+                "8.8.8.8:53".parse().unwrap(),
+                "1.1.1.1:53".parse().unwrap(),
+            ],
+            peers_dat: None,
+            anchors_dat: None,
+            address_list: None,
+            min_seed_addresses: 10,
+            min_online_nodes: 50,
+            store: StoreKind::Memory,
+            visit_retention_days: 30,
+            dns_listen: vec!["0.0.0.0:53".parse().unwrap()],
+            dns: DnsConfig::default(),
+            rrl: RrlConfig::default(),
+            dns_cookies: true,
+            user: None,
+            group: None,
+            chroot: false,
+            admin_socket: true,
+            path: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, io::Error> {
+        let contents = fs::read_to_string(path)?;
+        let mut config: Config = toml::from_str(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        config.validate()?;
+        config.path = Some(fs::canonicalize(path)?);
+        Ok(config)
+    }
+
+    // reject settings that parse but can't work
+    pub fn validate(&self) -> Result<(), io::Error> {
+        let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        if self.dns_bootstrap && self.bootstrap_resolvers.is_empty() {
+            return invalid("`dns_bootstrap` needs `bootstrap_resolvers`");
+        }
+        if self.chroot && self.user.is_none() {
+            return invalid("`chroot` needs a `user` to switch to");
+        }
+        if self.chroot && !self.data_dir.is_absolute() {
+            return invalid("`chroot` needs an absolute `data_dir`");
+        }
+        if self.dns.max_a_records > MAX_RECORDS || self.dns.max_aaaa_records > MAX_RECORDS {
+            return invalid(&format!(
+                "at most {} records fit in a DNS response",
+                MAX_RECORDS
+            ));
+        }
+        if self.rrl.ipv4_prefix_len > 32 || self.rrl.ipv6_prefix_len > 128 {
+            return invalid("rrl prefix length longer than an address");
+        }
+        Ok(())
+    }
+
+    // `new`, except for the settings only read on startup (sockets, storage
+    // and privileges), which are kept from `self`
+    pub fn reloaded(&self, new: Config) -> Config {
+        Config {
+            data_dir: self.data_dir.clone(),
+            http_listen: self.http_listen,
+            dns_listen: self.dns_listen.clone(),
+            store: self.store,
+            visit_retention_days: self.visit_retention_days,
+            user: self.user.clone(),
+            group: self.group.clone(),
+            chroot: self.chroot,
+            admin_socket: self.admin_socket,
+            path: self.path.clone(),
+            ..new
+        }
+    }
+
+    // the settings that differ in `new`, as "name: old -> new"
+    pub fn changes(&self, new: &Config) -> Vec<String> {
+        let mut changes = vec![];
+        macro_rules! compare {
+            ($($field:ident),*) => {$(
+                if self.$field != new.$field {
+                    changes.push(format!(
+                        "{}: {:?} -> {:?}",
+                        stringify!($field),
+                        self.$field,
+                        new.$field
+                    ));
+                }
+            )*};
+        }
+        compare!(
+            data_dir,
+            http_listen,
+            dns_bootstrap,
+            bootstrap_resolvers,
+            peers_dat,
+            anchors_dat,
+            address_list,
+            min_seed_addresses,
+            min_online_nodes,
+            store,
+            visit_retention_days,
+            dns_listen,
+            dns,
+            rrl,
+            dns_cookies,
+            user,
+            group,
+            chroot,
+            admin_socket
+        );
+        changes
+    }
+
+    // the same settings as seen from inside a chroot to `data_dir`; files
+    // elsewhere can't be reached anymore and are left as they are
+    pub fn chrooted(&self) -> Config {
+        let inside = |path: &PathBuf| match path.strip_prefix(&self.data_dir) {
+            Ok(relative) => Path::new("/").join(relative),
+            Err(_) => path.clone(),
+        };
+        Config {
+            data_dir: PathBuf::from("/"),
+            peers_dat: self.peers_dat.as_ref().map(inside),
+            anchors_dat: self.anchors_dat.as_ref().map(inside),
+            address_list: self.address_list.as_ref().map(inside),
+            path: self.path.as_ref().map(inside),
+            ..self.clone()
+        }
+    }
+
+    pub fn ban_file(&self) -> PathBuf {
+        self.data_dir.join("banlist.txt")
+    }
+
+    pub fn dump_file(&self) -> PathBuf {
+        self.data_dir.join("dnsseed.dump")
+    }
+
+    pub fn admin_socket_file(&self) -> PathBuf {
+        self.data_dir.join("admin.sock")
+    }
+
+    pub fn snapshot_file(&self) -> PathBuf {
+        self.data_dir.join("nodes.json")
+    }
+
+    pub fn sqlite_file(&self) -> PathBuf {
+        self.data_dir.join("nodes.sqlite")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload() {
+        let old = Config::default();
+        let mut new = Config::default();
+        new.min_online_nodes = 20;
+        new.dns.ttl = 600;
+        new.dns_listen = vec!["[::]:53".parse().unwrap()];
+
+        let applied = old.reloaded(new.clone());
+        assert_eq!(20, applied.min_online_nodes);
+        assert_eq!(old.dns_listen, applied.dns_listen);
+        assert_eq!(
+            vec![
+                String::from("min_online_nodes: 50 -> 20"),
+                String::from(
+                    "dns: DnsConfig { ttl: 3094, max_a_records: 25, max_aaaa_records: 16 } -> \
+                     DnsConfig { ttl: 600, max_a_records: 25, max_aaaa_records: 16 }"
+                ),
+            ],
+            old.changes(&applied)
+        );
+        // what has to wait for a restart
+        assert_eq!(
+            vec![String::from("dns_listen: [0.0.0.0:53] -> [[::]:53]")],
+            applied.changes(&new)
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+        let mut config = Config::default();
+        config.chroot = true;
+        assert!(config.validate().is_err());
+        let mut config = Config::default();
+        config.dns.max_aaaa_records = 100;
+        assert!(config.validate().is_err());
+        let mut config = Config::default();
+        config.user = Some(String::from("nobody"));
+        config.chroot = true;
+        assert!(config.validate().is_err());
+        config.data_dir = PathBuf::from("/var/lib/bitcoin-seed");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_reload_chrooted() {
+        let dir = fs::canonicalize(std::env::temp_dir())
+            .unwrap()
+            .join(format!("seed-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |min_online_nodes: usize| {
+            let contents = format!(
+                "data_dir = {:?}\nuser = \"nobody\"\nchroot = true\nmin_online_nodes = {}\n",
+                dir, min_online_nodes
+            );
+            fs::write(dir.join("seed.toml"), contents).unwrap();
+        };
+        write(20);
+        let config = Config::load(&dir.join("seed.toml")).unwrap().chrooted();
+        let path = config.path.clone().unwrap();
+        assert_eq!(Path::new("/seed.toml"), path);
+
+        // reloading opens `path` from inside the chroot, which is this
+        // file outside of it
+        write(30);
+        let inside = dir.join(path.strip_prefix("/").unwrap());
+        let applied = config.reloaded(Config::load(&inside).unwrap().chrooted());
+        assert_eq!(30, applied.min_online_nodes);
+        assert_eq!(Path::new("/"), applied.data_dir);
+        assert_eq!(Some(path), applied.path);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+use rand::RngCore;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::dns::{DnsPacket, DnsRecord, EdnsOption, ResultCode};
+use super::utils;
+
+// Server-side DNS cookies (RFC 7873), with server cookies in the
+// interoperable format of RFC 9018: version, reserved bytes, a timestamp and
+// a SipHash-2-4 of the client cookie and address under a secret we rotate
+// every hour. A client that echoes back a cookie we issued has shown it can
+// receive our responses, i.e. its address isn't spoofed, so it's exempt from
+// rate limiting.
+
+pub const COOKIE_OPTION: u16 = 10;
+pub const BADCOOKIE: u16 = 23;
+// advertised in our OPT records, the EDNS "flag day" recommendation
+pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+const CLIENT_COOKIE_LEN: usize = 8;
+const SERVER_COOKIE_LEN: usize = 16;
+const VERSION: u8 = 1;
+const ROTATE_EVERY: Duration = Duration::from_secs(60 * 60);
+// how old (or how far in the future) a server cookie's timestamp may be
+const MAX_AGE: u64 = 60 * 60;
+const MAX_SKEW: u64 = 5 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CookieStatus {
+    // no EDNS, or no cookie option
+    Absent,
+    // a cookie option with an impossible length, FORMERR
+    Malformed,
+    // just a client cookie, a first contact
+    New([u8; CLIENT_COOKIE_LEN]),
+    // a server cookie we issued to this client recently
+    Valid([u8; CLIENT_COOKIE_LEN]),
+    // a server cookie we didn't issue, or that has expired
+    Bad([u8; CLIENT_COOKIE_LEN]),
+}
+
+impl CookieStatus {
+    pub fn client(&self) -> Option<&[u8; CLIENT_COOKIE_LEN]> {
+        match self {
+            CookieStatus::New(c) | CookieStatus::Valid(c) | CookieStatus::Bad(c) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+struct Secrets {
+    current: [u8; 16],
+    previous: Option<[u8; 16]>,
+    rotated: Instant,
+}
+
+pub struct ServerCookies {
+    enabled: AtomicBool,
+    secrets: RwLock<Secrets>,
+}
+
+fn random_secret() -> [u8; 16] {
+    let mut secret = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+impl ServerCookies {
+    pub fn new(enabled: bool) -> ServerCookies {
+        ServerCookies {
+            enabled: AtomicBool::new(enabled),
+            secrets: RwLock::new(Secrets {
+                current: random_secret(),
+                previous: None,
+                rotated: Instant::now(),
+            }),
+        }
+    }
+
+    // when disabled, requests are treated as if they carried no cookie
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    // start signing with a fresh secret, still accepting the last one
+    pub fn rotate(&self) {
+        let mut secrets = self.secrets.write().unwrap();
+        secrets.previous = Some(secrets.current);
+        secrets.current = random_secret();
+        secrets.rotated = Instant::now();
+    }
+
+    fn rotate_if_due(&self) {
+        if self.secrets.read().unwrap().rotated.elapsed() >= ROTATE_EVERY {
+            self.rotate();
+        }
+    }
+
+    // classify the cookie (if any) in `request` from `ip`
+    pub fn check(&self, request: &DnsPacket, ip: IpAddr, now: SystemTime) -> CookieStatus {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return CookieStatus::Absent;
+        }
+        let option = request
+            .edns_options()
+            .and_then(|options| options.iter().find(|o| o.code == COOKIE_OPTION));
+        let data = match option {
+            Some(option) => &option.data,
+            None => return CookieStatus::Absent,
+        };
+        // a client cookie, optionally followed by an 8 to 32 byte server cookie
+        let server_len = data.len().wrapping_sub(CLIENT_COOKIE_LEN);
+        if data.len() < CLIENT_COOKIE_LEN
+            || (server_len != 0 && (server_len < 8 || server_len > 32))
+        {
+            return CookieStatus::Malformed;
+        }
+        let mut client = [0u8; CLIENT_COOKIE_LEN];
+        client.copy_from_slice(&data[..CLIENT_COOKIE_LEN]);
+        if server_len == 0 {
+            return CookieStatus::New(client);
+        }
+        if self.verify(&client, &data[CLIENT_COOKIE_LEN..], ip, now) {
+            CookieStatus::Valid(client)
+        } else {
+            CookieStatus::Bad(client)
+        }
+    }
+
+    fn verify(&self, client: &[u8; 8], server: &[u8], ip: IpAddr, now: SystemTime) -> bool {
+        if server.len() != SERVER_COOKIE_LEN || server[0] != VERSION {
+            return false;
+        }
+        let timestamp = u64::from(read_u32(&server[4..8]));
+        let now = utils::unix_time(now);
+        if timestamp + MAX_AGE < now || timestamp > now + MAX_SKEW {
+            return false;
+        }
+        let secrets = self.secrets.read().unwrap();
+        let mut keys = vec![secrets.current];
+        keys.extend(secrets.previous);
+        keys.iter()
+            .any(|key| server_cookie(key, client, &server[..8], ip)[8..] == server[8..])
+    }
+
+    // a fresh server cookie for `client` at `ip`
+    pub fn issue(&self, client: &[u8; 8], ip: IpAddr, now: SystemTime) -> Vec<u8> {
+        self.rotate_if_due();
+        let mut header = [0u8; 8];
+        header[0] = VERSION;
+        header[4..].copy_from_slice(&(utils::unix_time(now) as u32).to_be_bytes());
+        let key = self.secrets.read().unwrap().current;
+        server_cookie(&key, client, &header, ip)
+    }
+
+    // the OPT record for a response to a request with `status`, or None if
+    // the request didn't use EDNS
+    pub fn response_opt(
+        &self,
+        request: &DnsPacket,
+        status: &CookieStatus,
+        ip: IpAddr,
+        now: SystemTime,
+    ) -> Option<DnsRecord> {
+        request.edns_options()?;
+        let mut options = vec![];
+        if let Some(client) = status.client() {
+            let mut data = client.to_vec();
+            data.extend(self.issue(client, ip, now));
+            options.push(EdnsOption {
+                code: COOKIE_OPTION,
+                data,
+            });
+        }
+        Some(DnsRecord::OPT {
+            packet_len: UDP_PAYLOAD_SIZE,
+            flags: 0,
+            options,
+        })
+    }
+}
+
+// set an extended rcode (RFC 6891), whose upper bits live in the OPT record
+pub fn set_extended_rcode(packet: &mut DnsPacket, rcode: u16) {
+    packet.header.rescode = ResultCode::from_num((rcode & 0x0F) as u8);
+    for record in packet.resources.iter_mut() {
+        if let DnsRecord::OPT { ref mut flags, .. } = record {
+            *flags = (*flags & 0x00FF_FFFF) | (u32::from(rcode >> 4) << 24);
+        }
+    }
+}
+
+// `header` (version, reserved, timestamp) followed by the hash
+fn server_cookie(key: &[u8; 16], client: &[u8; 8], header: &[u8], ip: IpAddr) -> Vec<u8> {
+    let mut input = client.to_vec();
+    input.extend_from_slice(header);
+    match ip {
+        IpAddr::V4(ip) => input.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => input.extend_from_slice(&ip.octets()),
+    }
+    let mut cookie = header.to_vec();
+    cookie.extend_from_slice(&siphash24(key, &input).to_le_bytes());
+    cookie
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from(b[0]) << 24 | u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3])
+}
+
+fn read_u64_le(b: &[u8]) -> u64 {
+    b.iter()
+        .enumerate()
+        .fold(0, |acc, (i, byte)| acc | u64::from(*byte) << (8 * i))
+}
+
+// SipHash-2-4, as RFC 9018 asks for
+fn siphash24(key: &[u8; 16], data: &[u8]) -> u64 {
+    let k0 = read_u64_le(&key[..8]);
+    let k1 = read_u64_le(&key[8..]);
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = read_u64_le(chunk);
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+    let last = read_u64_le(chunks.remainder()) | (data.len() as u64) << 56;
+    v[3] ^= last;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= last;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuestion, QueryType};
+
+    fn request(cookie: Option<Vec<u8>>) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new(
+            String::from("seed.example.com"),
+            QueryType::A,
+        ));
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            options: cookie
+                .map(|data| EdnsOption {
+                    code: COOKIE_OPTION,
+                    data,
+                })
+                .into_iter()
+                .collect(),
+        });
+        packet
+    }
+
+    #[test]
+    fn test_siphash24() {
+        // reference vectors from the SipHash paper's test suite
+        let key: Vec<u8> = (0..16).collect();
+        let mut k = [0u8; 16];
+        k.copy_from_slice(&key);
+        assert_eq!(0x726f_db47_dd0e_0e31, siphash24(&k, &[]));
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(0xa129_ca61_49be_45e5, siphash24(&k, &data));
+    }
+
+    #[test]
+    fn test_cookie_lifecycle() {
+        let cookies = ServerCookies::new(true);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = SystemTime::now();
+        let client = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        assert_eq!(
+            CookieStatus::Absent,
+            cookies.check(&DnsPacket::new(), ip, now)
+        );
+        assert_eq!(CookieStatus::Absent, cookies.check(&request(None), ip, now));
+        assert_eq!(
+            CookieStatus::Malformed,
+            cookies.check(&request(Some(vec![1, 2, 3])), ip, now)
+        );
+        let first = request(Some(client.to_vec()));
+        assert_eq!(CookieStatus::New(client), cookies.check(&first, ip, now));
+
+        // echo the cookie from the response back
+        let opt = cookies
+            .response_opt(&first, &CookieStatus::New(client), ip, now)
+            .unwrap();
+        let full = match opt {
+            DnsRecord::OPT { options, .. } => options[0].data.clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(24, full.len());
+        let second = request(Some(full.clone()));
+        assert_eq!(CookieStatus::Valid(client), cookies.check(&second, ip, now));
+
+        // bound to the client's address, and its age
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_eq!(
+            CookieStatus::Bad(client),
+            cookies.check(&second, other, now)
+        );
+        let later = now + Duration::from_secs(2 * 60 * 60);
+        assert_eq!(CookieStatus::Bad(client), cookies.check(&second, ip, later));
+
+        // still good for one rotation, not two
+        cookies.rotate();
+        assert_eq!(CookieStatus::Valid(client), cookies.check(&second, ip, now));
+        cookies.rotate();
+        assert_eq!(CookieStatus::Bad(client), cookies.check(&second, ip, now));
+    }
+
+    #[test]
+    fn test_set_extended_rcode() {
+        let mut packet = request(None);
+        set_extended_rcode(&mut packet, BADCOOKIE);
+        assert_eq!(BADCOOKIE, packet.extended_rcode());
+    }
+}
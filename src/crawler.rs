@@ -1,3 +1,9 @@
+//! Visiting nodes over the Bitcoin P2P protocol and keeping the node
+//! database up to date.
+//!
+//! [`visit`] performs the handshake with a single node, [`worker`] loops
+//! over nodes due for a visit, and [`crawl`] runs the whole seeder.
+
 use bitcoin::consensus::encode::serialize;
 use bitcoin::network::constants::Network;
 use bitcoin::network::{
@@ -6,29 +12,107 @@ use bitcoin::network::{
     message_network::VersionMessage,
     stream_reader::StreamReader,
 };
-use log::{info, trace};
+use log::{error, info, trace, warn};
+use std::fs;
+use std::io;
 use std::io::Write;
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
+use super::admin;
+use super::answers::AnswerCache;
+use super::api;
+use super::ban;
+use super::config::{Config, StoreKind};
+use super::cookie::ServerCookies;
 use super::db;
 use super::dns;
+use super::dns::DnsConfig;
+use super::dump;
+use super::metrics::Metrics;
+use super::peers;
+use super::privs;
+use super::resolver::Resolver;
+use super::rrl::RateLimiter;
+use super::seeds;
+#[cfg(feature = "snapshot")]
+use super::snapshot;
+#[cfg(feature = "sqlite")]
+use super::sqlite;
 use super::utils;
 
-fn bootstrap(tdb: Arc<Mutex<db::NodeDb>>) {
-    let mut db = tdb.lock().unwrap();
-    let seeds = utils::dns_seed(Network::Bitcoin);
+pub use super::utils::CrawlerError;
+
+// don't hammer the bootstrap sources while the crawler is still catching up
+const REBOOTSTRAP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const ANSWERS_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60);
+const WORKER_THREADS: usize = 20;
+// how often the main loop checks for signals
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// how long visits in flight get to finish once we've been asked to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Seed the database from the configured address files, DNS seeds and, if
+/// those come up short, the compiled-in fixed seeds.
+pub fn bootstrap(tdb: Arc<RwLock<db::NodeDb>>, config: &Config) {
+    let network = Network::Bitcoin;
+    let mut seeds = vec![];
+    let files: [(&Option<PathBuf>, fn(&Path, Network) -> io::Result<Vec<SocketAddr>>); 3] = [
+        (&config.peers_dat, peers::read_peers_dat),
+        (&config.anchors_dat, peers::read_anchors_dat),
+        (&config.address_list, peers::read_address_list),
+    ];
+    for (path, read) in files.iter() {
+        if let Some(path) = path {
+            match read(path, network) {
+                Ok(addrs) => {
+                    info!("read {} addresses from {}", addrs.len(), path.display());
+                    seeds.extend(addrs);
+                }
+                Err(e) => error!("Couldn't read {}: {}", path.display(), e),
+            }
+        }
+    }
+    if config.dns_bootstrap {
+        let resolver = Resolver::new(
+            config.bootstrap_resolvers.clone(),
+            Duration::from_secs(2),
+            3,
+        );
+        seeds.extend(utils::dns_seed(network, &resolver));
+    }
+    if seeds.len() < config.min_seed_addresses {
+        let fixed = seeds::fixed_seeds(network);
+        info!(
+            "only {} bootstrap addresses, adding {} fixed seeds",
+            seeds.len(),
+            fixed.len()
+        );
+        if seeds.is_empty() && fixed.is_empty() {
+            error!("No addresses to crawl, and this build has no fixed seeds");
+        }
+        seeds.extend(fixed);
+    }
+    let mut db = tdb.write().unwrap();
     for addr in seeds {
         db.init(addr);
     }
 }
 
-fn visit(node: db::Node) -> Result<WorkerOutput, utils::CrawlerError> {
+/// Connect to `node`, exchange `version`/`verack` and wait for its answer to
+/// `getaddr`. A node that doesn't send a `version` comes back without one.
+pub fn visit(node: db::Node) -> Result<WorkerOutput, CrawlerError> {
     trace!("Connecting to {}", &node.addr);
     let mut worker_output = WorkerOutput::new(node.clone());
+    let start = Instant::now();
     let mut stream = TcpStream::connect_timeout(&node.addr, Duration::new(1, 0))?;
+    worker_output.connect_latency = Some(start.elapsed());
     trace!("Connected to {}", &node.addr);
 
     // timeout in 30 seconds
@@ -106,117 +190,528 @@ fn visit(node: db::Node) -> Result<WorkerOutput, utils::CrawlerError> {
     return Ok(worker_output);
 }
 
-struct WorkerOutput {
-    node: db::Node,
-    version_msg: Option<VersionMessage>,
-    addr_msg: Option<Vec<(u32, Address)>>,
+/// What [`visit`] learned about a node.
+pub struct WorkerOutput {
+    pub node: db::Node,
+    pub connect_latency: Option<Duration>,
+    pub version_msg: Option<VersionMessage>,
+    pub addr_msg: Option<Vec<(u32, Address)>>,
 }
 
 impl WorkerOutput {
     fn new(node: db::Node) -> WorkerOutput {
         WorkerOutput {
             node: node,
+            connect_latency: None,
             version_msg: None,
             addr_msg: None,
         }
     }
 }
 
-fn worker(tdb: Arc<Mutex<db::NodeDb>>) {
-    loop {
-        let mut db = tdb.lock().unwrap();
+/// Visit nodes as they come due and record the results, until `stop` is
+/// set. A visit in progress is finished first.
+pub fn worker(tdb: Arc<RwLock<db::NodeDb>>, metrics: Arc<Metrics>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::SeqCst) {
+        let mut db = tdb.write().unwrap();
         let next = db.next();
         drop(db);
         // if next, visit them. otherwise, sleep.
-        let result = match next {
-            Some(node) => visit(node),
-
+        match next {
+            Some(node) => {
+                let result = visit(node.clone());
+                record(&tdb, &metrics, node, result);
+            }
             None => {
                 trace!("going to sleep");
                 thread::sleep(Duration::new(1, 0));
                 trace!("waking up");
-                continue;
             }
-        };
-        // if `version_msg` present in output, mark node online. otherwise,
-        // mark them offline
-        match result {
-            Ok(mut output) => {
-                match output.version_msg {
-                    Some(_) => {
-                        output.node.state = db::NodeState::Online;
-                        let mut db = tdb.lock().unwrap();
-                        db.insert(output.node);
-                    }
-                    None => {
-                        output.node.state = db::NodeState::Offline;
-                        let mut db = tdb.lock().unwrap();
-                        db.insert(output.node);
-                    }
+        }
+    }
+}
+
+/// Store the outcome of a [`visit`] to `node`: its new state and version,
+/// a visit log entry and the addresses it told us about.
+pub fn record(
+    tdb: &RwLock<db::NodeDb>,
+    metrics: &Metrics,
+    node: db::Node,
+    result: Result<WorkerOutput, CrawlerError>,
+) {
+    // if `version_msg` present in output, mark node online. otherwise,
+    // mark them offline
+    match result {
+        Ok(mut output) => {
+            let source = output.node.addr;
+            if let Some(latency) = output.connect_latency {
+                metrics.connect_latency.observe_duration(latency);
+            }
+            match output.version_msg {
+                Some(ref version) => {
+                    metrics.visits.inc(&["online"]);
+                    output.node.state = db::NodeState::Online;
+                    output.node.stats.record_visit(true, SystemTime::now());
+                    output.node.version = Some(db::VersionInfo {
+                        version: version.version,
+                        services: version.services,
+                        user_agent: version.user_agent.clone(),
+                        start_height: version.start_height,
+                    });
+                    let visit = db::Visit::new(
+                        &output.node,
+                        db::VisitOutcome::Online,
+                        output.connect_latency,
+                    );
+                    let mut db = tdb.write().unwrap();
+                    db.record_visit(&visit);
+                    db.insert(output.node);
                 }
-                // if addr_msg present on `output`, initialize these records in db
-                match output.addr_msg {
-                    Some(addr_msg) => {
-                        for net_addr in addr_msg {
-                            match net_addr.1.socket_addr() {
-                                Ok(addr) => {
-                                    let mut db = tdb.lock().unwrap();
-                                    db.init(addr);
-                                }
-                                Err(_) => (),
-                            }
-                        }
-                    }
-                    None => (),
+                None => {
+                    metrics.visits.inc(&["offline"]);
+                    output.node.state = db::NodeState::Offline;
+                    output.node.stats.record_visit(false, SystemTime::now());
+                    let visit = db::Visit::new(
+                        &output.node,
+                        db::VisitOutcome::Offline,
+                        output.connect_latency,
+                    );
+                    let mut db = tdb.write().unwrap();
+                    db.record_visit(&visit);
+                    db.insert(output.node);
+                }
+            }
+            // if addr_msg present on `output`, initialize these records in db,
+            // all under one lock so DNS readers aren't stalled per address
+            match output.addr_msg {
+                Some(addr_msg) => {
+                    metrics.addresses_learned.inc_by(addr_msg.len() as u64);
+                    let addrs: Vec<SocketAddr> = addr_msg
+                        .iter()
+                        .filter_map(|net_addr| net_addr.1.socket_addr().ok())
+                        .collect();
+                    let mut db = tdb.write().unwrap();
+                    db.init_all_from(&addrs, source);
                 }
+                None => (),
             }
-            Err(err) => trace!("Crawler error: {}", err),
+        }
+        Err(err) => {
+            metrics.visits.inc(&["error"]);
+            trace!("Crawler error: {}", err);
+            // couldn't even connect, which counts against its uptime
+            let mut node = node;
+            node.state = db::NodeState::Offline;
+            node.stats.record_visit(false, SystemTime::now());
+            let visit = db::Visit::new(&node, db::VisitOutcome::Error, None);
+            let mut db = tdb.write().unwrap();
+            db.record_visit(&visit);
+            db.insert(node);
         }
     }
 }
 
-fn spawn_worker_threads(tdb: Arc<Mutex<db::NodeDb>>, nthreads: i32) {
+// each worker sends on the returned channel once it has stopped
+fn spawn_worker_threads(
+    tdb: Arc<RwLock<db::NodeDb>>,
+    metrics: Arc<Metrics>,
+    nthreads: usize,
+    stop: Arc<AtomicBool>,
+) -> Receiver<()> {
     log::info!("Starting {} worker threads", nthreads);
+    let (done_tx, done) = mpsc::channel();
     for i in 0..nthreads {
         let db = Arc::clone(&tdb);
+        let metrics = Arc::clone(&metrics);
+        let stop = Arc::clone(&stop);
+        let done_tx = done_tx.clone();
         thread::Builder::new()
             .name(format!("thread-{}", i.to_string()))
             .spawn(move || {
-                worker(db);
+                worker(db, metrics, stop);
+                let _ = done_tx.send(());
             })
             .expect("Couldn't spawn worker thread");
     }
+    done
+}
+
+// keep the DNS answers in step with the crawler
+fn spawn_answers_thread(tdb: Arc<RwLock<db::NodeDb>>, answers: Arc<AnswerCache>) {
+    log::info!("Starting answer cache thread");
+    let port = utils::default_port(Network::Bitcoin);
+    thread::Builder::new()
+        .name(String::from("answers"))
+        .spawn(move || loop {
+            let db = tdb.read().unwrap();
+            answers.refresh(&db, port);
+            drop(db);
+            thread::sleep(ANSWERS_REFRESH_INTERVAL);
+        })
+        .expect("Couldn't spawn answer cache thread");
+}
+
+// bootstrapping can take minutes when DNS seeds don't answer, so it gets a
+// thread of its own and never keeps the main loop from seeing `stop`
+fn spawn_bootstrap_thread(tdb: Arc<RwLock<db::NodeDb>>, config: Config) {
+    thread::Builder::new()
+        .name(String::from("bootstrap"))
+        .spawn(move || {
+            bootstrap(tdb, &config);
+        })
+        .expect("Couldn't spawn bootstrap thread");
+}
+
+fn spawn_http_thread(tdb: Arc<RwLock<db::NodeDb>>, metrics: Arc<Metrics>, addr: SocketAddr) {
+    log::info!("Starting HTTP thread on {}", addr);
+    thread::Builder::new()
+        .name(String::from("http"))
+        .spawn(move || {
+            api::serve(tdb, metrics, addr);
+        })
+        .expect("Couldn't spawn http thread");
 }
 
-fn spawn_dns_thread(tdb: Arc<Mutex<db::NodeDb>>) {
-    log::info!("Starting DNS thread");
-    let _db = Arc::clone(&tdb);
+// returns the socket's path, to be removed again on shutdown; the seeder runs
+// fine without it
+fn spawn_admin_thread(
+    tdb: Arc<RwLock<db::NodeDb>>,
+    metrics: Arc<Metrics>,
+    config: &Config,
+) -> Option<PathBuf> {
+    let path = config.admin_socket_file();
+    let listener = match admin::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Couldn't bind admin socket {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    log::info!("Starting admin thread on {}", path.display());
+    let dump_file = config.dump_file();
     thread::Builder::new()
-        .name(String::from("dns"))
+        .name(String::from("admin"))
         .spawn(move || {
-            println!("spawning dns thread");
-            dns::serve(_db);
+            admin::serve(listener, tdb, metrics, dump_file);
         })
-        .expect("Couldn't spawn worker thread");
+        .expect("Couldn't spawn admin thread");
+    Some(path)
+}
+
+fn open_store(config: &Config) -> Result<Box<dyn db::NodeStore>, io::Error> {
+    match config.store {
+        StoreKind::Memory => Ok(Box::new(db::MemoryStore::new())),
+        #[cfg(feature = "snapshot")]
+        StoreKind::Snapshot => Ok(Box::new(snapshot::SnapshotStore::open(
+            &config.snapshot_file(),
+        )?)),
+        #[cfg(not(feature = "snapshot"))]
+        StoreKind::Snapshot => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "built without the `snapshot` feature",
+        )),
+        #[cfg(feature = "sqlite")]
+        StoreKind::Sqlite => Ok(Box::new(sqlite::SqliteStore::open(
+            &config.sqlite_file(),
+            Duration::from_secs(config.visit_retention_days * 24 * 60 * 60),
+        )?)),
+        #[cfg(not(feature = "sqlite"))]
+        StoreKind::Sqlite => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "built without the `sqlite` feature",
+        )),
+    }
 }
 
-pub fn crawl() {
+// give up root now that the DNS sockets are bound, returning the config as
+// seen from the new root directory
+fn drop_privileges(config: Config) -> Result<Config, io::Error> {
+    let user = match config.user {
+        Some(ref user) => user,
+        None => {
+            if privs::is_root() {
+                warn!("Running as root, set `user` to drop privileges");
+            }
+            return Ok(config);
+        }
+    };
+    let chroot = if config.chroot {
+        Some(config.data_dir.as_path())
+    } else {
+        None
+    };
+    // SIGHUP re-reads the config, which only works if it's still there
+    if let (Some(dir), Some(path)) = (chroot, &config.path) {
+        if !path.starts_with(dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is outside `data_dir` and couldn't be reloaded after the chroot",
+                    path.display()
+                ),
+            ));
+        }
+    }
+    privs::drop_privileges(user, config.group.as_ref().map(|g| g.as_str()), chroot)?;
+    info!("Running as {}", user);
+    if !config.chroot {
+        return Ok(config);
+    }
+    info!("Confined to {}", config.data_dir.display());
+    for path in [&config.peers_dat, &config.anchors_dat, &config.address_list].iter() {
+        if let Some(path) = path {
+            if !path.starts_with(&config.data_dir) {
+                warn!("{} is outside the chroot and can't be read", path.display());
+            }
+        }
+    }
+    Ok(config.chrooted())
+}
+
+/// Flush the node store and write the dump to `dump_file`, trying both even
+/// if one fails.
+pub fn save(tdb: &RwLock<db::NodeDb>, dump_file: &Path) -> Result<(), io::Error> {
+    let db = tdb.read().unwrap();
+    let nodes = db.nodes();
+    save_nodes(db, nodes, dump_file)
+}
+
+// `save` with `nodes` already copied out of the table under `db`, which is
+// released before the dump is formatted and written
+fn save_nodes(
+    db: RwLockReadGuard<db::NodeDb>,
+    nodes: Vec<db::Node>,
+    dump_file: &Path,
+) -> Result<(), io::Error> {
+    let nodes = dump::mark_good(&db, nodes, utils::default_port(Network::Bitcoin));
+    let flushed = db.flush();
+    drop(db);
+    let written = utils::write_atomic(dump_file, &dump::format_dump(nodes));
+    flushed.and(written)
+}
+
+// the DNS and crawler settings that can change on SIGHUP
+struct Reloadable {
+    rrl: Arc<RateLimiter>,
+    cookies: Arc<ServerCookies>,
+    dns: Arc<RwLock<DnsConfig>>,
+}
+
+// re-read the ban list and the config file, returning the config to carry on
+// with; one that doesn't load or validate changes nothing
+fn reload(config: &Config, tdb: &RwLock<db::NodeDb>, reloadable: &Reloadable) -> Config {
+    info!("Reloading");
+    let mut db = tdb.write().unwrap();
+    let before = db.bans().rules().len();
+    match db.bans_mut().reload() {
+        Ok(()) => info!(
+            "Reloaded ban list: {} -> {} rules",
+            before,
+            db.bans().rules().len()
+        ),
+        Err(e) => error!("Couldn't reload ban list, keeping the old one: {}", e),
+    }
+    drop(db);
+
+    let path = match config.path {
+        Some(ref path) => path,
+        None => return config.clone(),
+    };
+    let mut new = match Config::load(path) {
+        Ok(new) => new,
+        Err(e) => {
+            error!(
+                "Couldn't reload {}, keeping the old config: {}",
+                path.display(),
+                e
+            );
+            return config.clone();
+        }
+    };
+    if config.chroot {
+        new = new.chrooted();
+    }
+    let applied = config.reloaded(new.clone());
+    let changes = config.changes(&applied);
+    if changes.is_empty() {
+        info!("Reloaded config, nothing changed");
+    } else {
+        info!("Reloaded config: {}", changes.join(", "));
+    }
+    let ignored = applied.changes(&new);
+    if !ignored.is_empty() {
+        warn!("Not applied until restart: {}", ignored.join(", "));
+    }
+
+    if applied.rrl != config.rrl {
+        reloadable.rrl.reconfigure(applied.rrl.clone());
+    }
+    reloadable.cookies.set_enabled(applied.dns_cookies);
+    *reloadable.dns.write().unwrap() = applied.dns.clone();
+    applied
+}
+
+/// Run the seeder: the DNS server, the optional HTTP API, the crawler
+/// workers and the periodic housekeeping loop, until SIGTERM or SIGINT.
+/// SIGHUP reloads the ban list and whatever settings can change at runtime.
+pub fn crawl(config: Config) {
     utils::init_logger();
-    let db = db::NodeDb::new();
-    let tdb = Arc::new(Mutex::new(db));
-    spawn_dns_thread(tdb.clone());
-    thread::sleep(Duration::new(5, 0)); // make sure DNS thread starts (FIXME)
-    spawn_worker_threads(tdb.clone(), 20);
-    bootstrap(tdb.clone());
-    loop {
-        thread::sleep(Duration::new(60, 0));
-        let _db = tdb.lock().unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let hangup = Arc::new(AtomicBool::new(false));
+    let signals = [
+        (signal_hook::SIGTERM, &stop),
+        (signal_hook::SIGINT, &stop),
+        (signal_hook::SIGHUP, &hangup),
+    ];
+    for (signal, flag) in signals.iter() {
+        if let Err(e) = signal_hook::flag::register(*signal, Arc::clone(flag)) {
+            error!("Couldn't install signal handler: {}", e);
+            return;
+        }
+    }
+    // use the sockets systemd bound for us, or bind them while we may still
+    // be root, nothing else needs it
+    let listeners = match dns::Listeners::from_systemd() {
+        Ok(Some(listeners)) => {
+            info!(
+                "Using {} UDP and {} TCP DNS sockets from systemd",
+                listeners.udp.len(),
+                listeners.tcp.len()
+            );
+            Ok(listeners)
+        }
+        Ok(None) => dns::Listeners::bind(&config.dns_listen),
+        Err(e) => Err(e),
+    };
+    let listeners = match listeners {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            error!("Couldn't bind DNS listener: {}", e);
+            return;
+        }
+    };
+    let mut config = match drop_privileges(config) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Couldn't drop privileges: {}", e);
+            return;
+        }
+    };
+    let store = match open_store(&config) {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Couldn't open node store: {}", e);
+            return;
+        }
+    };
+    let mut db = db::NodeDb::with_store(store);
+    match ban::BanManager::load(&config.ban_file()) {
+        Ok(bans) => db.set_bans(bans),
+        Err(e) => error!("Couldn't load ban list: {}", e),
+    }
+    let tdb = Arc::new(RwLock::new(db));
+    let metrics = Arc::new(Metrics::new());
+    let answers = Arc::new(AnswerCache::new());
+    spawn_answers_thread(tdb.clone(), answers.clone());
+    let reloadable = Reloadable {
+        rrl: Arc::new(RateLimiter::new(config.rrl.clone())),
+        cookies: Arc::new(ServerCookies::new(config.dns_cookies)),
+        dns: Arc::new(RwLock::new(config.dns.clone())),
+    };
+    log::info!("Starting DNS server");
+    dns::serve(
+        listeners,
+        answers.clone(),
+        reloadable.rrl.clone(),
+        reloadable.cookies.clone(),
+        reloadable.dns.clone(),
+        metrics.clone(),
+        stop.clone(),
+    );
+    if let Some(addr) = config.http_listen {
+        spawn_http_thread(tdb.clone(), metrics.clone(), addr);
+    }
+    let admin_socket = if config.admin_socket {
+        spawn_admin_thread(tdb.clone(), metrics.clone(), &config)
+    } else {
+        None
+    };
+    let done = spawn_worker_threads(tdb.clone(), metrics.clone(), WORKER_THREADS, stop.clone());
+    spawn_bootstrap_thread(tdb.clone(), config.clone());
+    let mut last_bootstrap = Instant::now();
+    let mut last_housekeeping = Instant::now();
+    while !stop.load(Ordering::SeqCst) {
+        thread::sleep(STOP_POLL_INTERVAL);
+        if hangup.swap(false, Ordering::SeqCst) {
+            config = reload(&config, &tdb, &reloadable);
+        }
+        if last_housekeeping.elapsed() < HOUSEKEEPING_INTERVAL {
+            continue;
+        }
+        last_housekeeping = Instant::now();
+        // running low on nodes, give the crawler something new to work with
+        let online = tdb.read().unwrap().report()[&db::NodeState::Online] as usize;
+        if online < config.min_online_nodes && last_bootstrap.elapsed() > REBOOTSTRAP_INTERVAL {
+            info!("only {} nodes online, bootstrapping again", online);
+            spawn_bootstrap_thread(tdb.clone(), config.clone());
+            last_bootstrap = Instant::now();
+        }
+        let mut _db = tdb.write().unwrap();
+        if let Err(e) = _db.bans_mut().reload_if_changed() {
+            error!("Couldn't reload ban list: {}", e);
+        }
+        _db.bans_mut().prune_expired();
+        drop(_db);
+        // the rest only reads, and works from one copy of the table so the
+        // workers recording visits wait for that and nothing more
+        let _db = tdb.read().unwrap();
+        let nodes = _db.nodes();
         let report = _db.report();
+        let other_ports =
+            db::count_online_on_other_ports(&nodes, utils::default_port(Network::Bitcoin));
+        let polluting = _db.polluting_sources(&nodes, 50, 0.05);
+        if let Err(e) = save_nodes(_db, nodes, &config.dump_file()) {
+            error!("Couldn't save nodes: {}", e);
+        }
         info!(
-            "Online: {:?} Offline: {:?} Uncontacted {:?}",
+            "Online: {:?} Offline: {:?} Uncontacted {:?} Online on non-default port: {}",
             report.get(&db::NodeState::Online).unwrap(),
             report.get(&db::NodeState::Offline).unwrap(),
             report.get(&db::NodeState::Uncontacted).unwrap(),
+            other_ports,
         );
+        for source in polluting {
+            info!(
+                "Suspicious source {}: {} addrs contributed, {} of {} contacted reachable",
+                source.source, source.contributed, source.reachable, source.contacted,
+            );
+        }
+    }
+
+    // the workers and the DNS server saw `stop` too, give visits in flight a
+    // chance to land before saving for the last time; a bootstrap still
+    // waiting on DNS seeds is left behind
+    info!("Shutting down");
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    let mut finished = 0;
+    while finished < WORKER_THREADS {
+        let now = Instant::now();
+        if now >= deadline || done.recv_timeout(deadline - now).is_err() {
+            break;
+        }
+        finished += 1;
+    }
+    if finished < WORKER_THREADS {
+        warn!(
+            "{} visits still running, not waiting for them",
+            WORKER_THREADS - finished
+        );
+    }
+    if let Some(ref path) = admin_socket {
+        let _ = fs::remove_file(path);
+    }
+    match save(&tdb, &config.dump_file()) {
+        Ok(()) => info!("Saved nodes, exiting"),
+        Err(e) => error!("Couldn't save nodes: {}", e),
     }
 }
@@ -1,31 +1,275 @@
-use std::collections::HashMap;
+//! The in-memory table of every node the crawler knows about, with the
+//! uptime statistics and provenance used to pick nodes for DNS answers.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
 use std::net::SocketAddr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Eq, Debug, PartialEq, Clone, Hash)] // FIXME
+use serde::{Deserialize, Serialize};
+
+use super::ban::BanManager;
+
+/// Outcome of the most recent visit to a node.
+#[derive(Eq, Debug, PartialEq, Clone, Hash, Serialize, Deserialize)] // FIXME
 pub enum NodeState {
     Online,
     Offline,
     Uncontacted,
 }
 
-#[derive(PartialEq, Eq, Debug, Clone)] // FIXME
+/// What a node told us about itself in its last `version` message.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: u32,
+    pub services: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+}
+
+/// Exponentially decaying uptime over one time window, the same estimator
+/// bitcoin-seeder uses: every visit folds in whether the node was reachable,
+/// weighted by how long ago the previous visit was relative to `tau`.
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UptimeWindow {
+    pub reliability: f64,
+    pub weight: f64,
+    pub count: f64,
+}
+
+impl UptimeWindow {
+    fn update(&mut self, good: bool, age: Duration, tau: Duration) {
+        let f = (-age.as_secs_f64() / tau.as_secs_f64()).exp();
+        self.reliability = self.reliability * f + if good { 1.0 - f } else { 0.0 };
+        self.count = self.count * f + 1.0;
+        self.weight = self.weight * f + (1.0 - f);
+    }
+}
+
+/// Uptime windows in seconds: 2 hours, 8 hours, 1 day, 1 week, 30 days.
+pub const UPTIME_WINDOWS: [u64; 5] = [2 * 3600, 8 * 3600, 24 * 3600, 7 * 24 * 3600, 30 * 24 * 3600];
+
+/// Visit history of a node, one [`UptimeWindow`] per entry of [`UPTIME_WINDOWS`].
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NodeStats {
+    pub last_success: Option<SystemTime>,
+    pub last_update: Option<SystemTime>,
+    pub uptime: [UptimeWindow; 5],
+}
+
+impl NodeStats {
+    /// Record the outcome of a visit that finished at `now`.
+    pub fn record_visit(&mut self, good: bool, now: SystemTime) {
+        let age = match self.last_update {
+            Some(last) => now.duration_since(last).unwrap_or_default(),
+            None => Duration::new(0, 0),
+        };
+        for (window, tau) in self.uptime.iter_mut().zip(UPTIME_WINDOWS.iter()) {
+            window.update(good, age, Duration::new(*tau, 0));
+        }
+        self.last_update = Some(now);
+        if good {
+            self.last_success = Some(now);
+        }
+    }
+}
+
+/// A known address and everything we've learned by visiting it.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)] // FIXME
 pub struct Node {
     pub addr: SocketAddr,
     pub state: NodeState,
     pub last_visit: SystemTime,
+    pub version: Option<VersionInfo>,
+    pub stats: NodeStats,
 }
 
-pub struct NodeDb {
+impl Node {
+    /// An address we haven't contacted yet.
+    pub fn new(addr: SocketAddr) -> Node {
+        Node {
+            addr: addr,
+            state: NodeState::Uncontacted,
+            last_visit: UNIX_EPOCH,
+            version: None,
+            stats: NodeStats::default(),
+        }
+    }
+}
+
+/// Who first told us about an address, and when we last heard about it.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Provenance {
+    pub source: SocketAddr,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+}
+
+/// How useful the addresses gossiped by a single peer turned out to be.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SourceStats {
+    pub source: SocketAddr,
+    pub contributed: usize,
+    pub contacted: usize,
+    pub reachable: usize,
+}
+
+impl SourceStats {
+    /// Fraction of the contacted addresses that were online (0 if none contacted yet).
+    pub fn reachable_ratio(&self) -> f64 {
+        if self.contacted == 0 {
+            return 0.0;
+        }
+        self.reachable as f64 / self.contacted as f64
+    }
+}
+
+/// How a visit to a node went.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum VisitOutcome {
+    /// Completed the handshake.
+    Online,
+    /// Connected, but never got a `version`.
+    Offline,
+    /// Couldn't connect, or the connection failed.
+    Error,
+}
+
+impl VisitOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VisitOutcome::Online => "online",
+            VisitOutcome::Offline => "offline",
+            VisitOutcome::Error => "error",
+        }
+    }
+}
+
+/// One entry in the visit log.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Visit {
+    pub time: SystemTime,
+    pub addr: SocketAddr,
+    pub outcome: VisitOutcome,
+    pub latency: Option<Duration>,
+    pub version: Option<VersionInfo>,
+}
+
+impl Visit {
+    /// A visit to `node` that finished just now.
+    pub fn new(node: &Node, outcome: VisitOutcome, latency: Option<Duration>) -> Visit {
+        Visit {
+            time: SystemTime::now(),
+            addr: node.addr,
+            outcome,
+            latency,
+            // whatever an unreachable node said last time is stale
+            version: match outcome {
+                VisitOutcome::Online => node.version.clone(),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Where node records live. `NodeDb` applies bans and tracks provenance on
+/// top, so implementations only have to store and query nodes.
+pub trait NodeStore: Send + Sync {
+    fn get(&self, addr: &SocketAddr) -> Option<Node>;
+    fn nodes(&self) -> Vec<Node>;
+    /// Add `addr` as uncontacted if it isn't known yet.
+    fn init(&mut self, addr: SocketAddr);
+    /// `init` for each of `addrs`; stores that can should do it as one batch.
+    fn init_all(&mut self, addrs: &[SocketAddr]) {
+        for addr in addrs {
+            self.init(*addr);
+        }
+    }
+    /// Add or replace a node.
+    fn insert(&mut self, node: Node);
+    /// A node last visited before `due`, not rejected by `skip`, with its
+    /// `last_visit` set to now so other workers don't pick it too.
+    fn next(&mut self, due: SystemTime, skip: &dyn Fn(&SocketAddr) -> bool) -> Option<Node>;
+    /// Up to `n` online nodes on `port`, not rejected by `skip`.
+    fn fetch_online_nodes(
+        &self,
+        n: usize,
+        port: u16,
+        skip: &dyn Fn(&SocketAddr) -> bool,
+    ) -> Vec<Node>;
+    /// Number of nodes in each state, every state present.
+    fn report(&self) -> HashMap<NodeState, i32>;
+    /// Append to the visit log, if the store keeps one.
+    fn record_visit(&mut self, _visit: &Visit) {}
+    /// Make everything inserted so far durable.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps nodes in a `HashMap`, lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
     nodes: HashMap<SocketAddr, Node>,
+    // every node ordered by `last_visit`, so `next` doesn't walk the map
+    by_last_visit: BTreeSet<(SystemTime, SocketAddr)>,
 }
 
-impl NodeDb {
-    pub fn new() -> NodeDb {
-        let mut nodes: HashMap<SocketAddr, Node> = HashMap::new();
-        NodeDb { nodes }
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
     }
-    pub fn report(&self) -> HashMap<NodeState, i32> {
+}
+
+impl NodeStore for MemoryStore {
+    fn get(&self, addr: &SocketAddr) -> Option<Node> {
+        self.nodes.get(addr).cloned()
+    }
+    fn nodes(&self) -> Vec<Node> {
+        self.nodes.values().cloned().collect()
+    }
+    fn init(&mut self, addr: SocketAddr) {
+        if !self.nodes.contains_key(&addr) {
+            self.insert(Node::new(addr));
+        }
+    }
+    fn insert(&mut self, node: Node) {
+        if let Some(old) = self.nodes.get(&node.addr) {
+            self.by_last_visit.remove(&(old.last_visit, old.addr));
+        }
+        self.by_last_visit.insert((node.last_visit, node.addr));
+        self.nodes.insert(node.addr, node);
+    }
+    fn next(&mut self, due: SystemTime, skip: &dyn Fn(&SocketAddr) -> bool) -> Option<Node> {
+        let addr = self
+            .by_last_visit
+            .iter()
+            .take_while(|(last_visit, _)| *last_visit < due)
+            .map(|(_, addr)| *addr)
+            .find(|addr| !skip(addr))?;
+        let mut node = self.nodes[&addr].clone();
+        node.last_visit = SystemTime::now();
+        self.insert(node.clone());
+        Some(node)
+    }
+    fn fetch_online_nodes(
+        &self,
+        n: usize,
+        port: u16,
+        skip: &dyn Fn(&SocketAddr) -> bool,
+    ) -> Vec<Node> {
+        let mut nodes = vec![];
+        for (_, node) in self.nodes.iter() {
+            if node.state == NodeState::Online && node.addr.port() == port && !skip(&node.addr) {
+                nodes.push(node.clone());
+                if nodes.len() == n {
+                    break;
+                }
+            }
+        }
+        nodes
+    }
+    fn report(&self) -> HashMap<NodeState, i32> {
         let mut report: HashMap<NodeState, i32> = HashMap::new();
 
         // initialize here so we know all keys always present
@@ -33,56 +277,181 @@ impl NodeDb {
         report.insert(NodeState::Offline, 0);
         report.insert(NodeState::Uncontacted, 0);
 
-        // acquire the db lock and build up the report map
         for (_, node) in self.nodes.iter() {
             let mut count = report.entry(node.state.clone()).or_insert(0); // FIXME already initialized ...
             *count += 1; // why?
         }
         report
     }
-    // get `n` online nodes (TODO iterate more randomly?)
-    pub fn fetch_online_nodes(&self, n: usize) -> Vec<Node> {
-        let mut nodes = vec![];
-        for (_, node) in self.nodes.iter() {
-            if node.state == NodeState::Online {
-                nodes.push(node.clone());
-                if nodes.len() == n {
-                    break;
-                }
-            }
+}
+
+/// All known nodes, keyed by address. Shared between the crawler, DNS and
+/// HTTP threads as `Arc<RwLock<NodeDb>>`.
+pub struct NodeDb {
+    store: Box<dyn NodeStore>,
+    provenance: HashMap<SocketAddr, Provenance>,
+    bans: BanManager,
+}
+
+impl NodeDb {
+    pub fn new() -> NodeDb {
+        NodeDb::with_store(Box::new(MemoryStore::new()))
+    }
+    pub fn with_store(store: Box<dyn NodeStore>) -> NodeDb {
+        let provenance: HashMap<SocketAddr, Provenance> = HashMap::new();
+        NodeDb {
+            store,
+            provenance,
+            bans: BanManager::new(),
         }
-        nodes
     }
-    // get next `n` nodes due for a visit, sets `last_visit` to now
+    pub fn set_bans(&mut self, bans: BanManager) {
+        self.bans = bans;
+    }
+    pub fn bans(&self) -> &BanManager {
+        &self.bans
+    }
+    pub fn bans_mut(&mut self) -> &mut BanManager {
+        &mut self.bans
+    }
+    pub fn get(&self, addr: &SocketAddr) -> Option<Node> {
+        self.store.get(addr)
+    }
+    pub fn nodes(&self) -> Vec<Node> {
+        self.store.nodes()
+    }
+    pub fn report(&self) -> HashMap<NodeState, i32> {
+        self.store.report()
+    }
+    /// Get `n` online nodes listening on `port` (TODO iterate more randomly?)
+    ///
+    /// DNS answers only carry IPs, so clients will always connect to the
+    /// network's default port and nodes on any other port are useless to them.
+    pub fn fetch_online_nodes(&self, n: usize, port: u16) -> Vec<Node> {
+        let bans = &self.bans;
+        self.store
+            .fetch_online_nodes(n, port, &|addr| bans.is_banned(addr))
+    }
+    /// Number of online nodes left out of DNS answers because they don't
+    /// listen on `port`.
+    pub fn count_online_on_other_ports(&self, port: u16) -> usize {
+        count_online_on_other_ports(&self.store.nodes(), port)
+    }
+    /// Get the next node due for a visit, setting its `last_visit` to now.
     pub fn next(&mut self) -> Option<Node> {
-        let now = SystemTime::now();
-        let one_hour_ago = now - Duration::new(60 * 60, 0);
-        for (_, node) in &mut self.nodes.iter() {
-            if node.last_visit < one_hour_ago {
-                let mut n = node.clone();
-                n.last_visit = SystemTime::now();
-                self.insert(n.clone());
-                return Some(n);
-            }
-        }
-        None
+        let one_hour_ago = SystemTime::now() - Duration::new(60 * 60, 0);
+        let bans = &self.bans;
+        self.store.next(one_hour_ago, &|addr| bans.is_banned(addr))
     }
-    // maybe this should be call "update"
+    /// Add or replace a node. (maybe this should be call "update")
     pub fn insert(&mut self, node: Node) {
-        self.nodes.insert(node.addr, node);
+        self.store.insert(node);
     }
+    /// Start tracking `addr` as uncontacted, unless it's known or banned.
     pub fn init(&mut self, addr: SocketAddr) {
-        if !self.nodes.contains_key(&addr) {
-            self.nodes.insert(
-                addr,
-                Node {
-                    addr: addr,
-                    state: NodeState::Uncontacted,
-                    last_visit: UNIX_EPOCH,
-                },
-            );
+        if self.bans.is_banned(&addr) {
+            return;
+        }
+        self.store.init(addr);
+    }
+    /// `init_from` for every address in one `addr` message, handed to the
+    /// store as one batch.
+    pub fn init_all_from(&mut self, addrs: &[SocketAddr], source: SocketAddr) {
+        let allowed: Vec<SocketAddr> = addrs
+            .iter()
+            .cloned()
+            .filter(|addr| !self.bans.is_banned(addr))
+            .collect();
+        self.store.init_all(&allowed);
+        let now = SystemTime::now();
+        for addr in allowed {
+            let provenance = self.provenance.entry(addr).or_insert(Provenance {
+                source,
+                first_seen: now,
+                last_seen: now,
+            });
+            provenance.last_seen = now;
+        }
+    }
+    /// Like `init`, but also records that `source` gossiped `addr` to us.
+    pub fn init_from(&mut self, addr: SocketAddr, source: SocketAddr) {
+        if self.bans.is_banned(&addr) {
+            return;
+        }
+        self.init(addr);
+        let now = SystemTime::now();
+        let provenance = self.provenance.entry(addr).or_insert(Provenance {
+            source,
+            first_seen: now,
+            last_seen: now,
+        });
+        provenance.last_seen = now;
+    }
+    pub fn record_visit(&mut self, visit: &Visit) {
+        self.store.record_visit(visit);
+    }
+    /// Persist the node table, a no-op for in-memory stores.
+    pub fn flush(&self) -> io::Result<()> {
+        self.store.flush()
+    }
+    pub fn provenance(&self, addr: &SocketAddr) -> Option<&Provenance> {
+        self.provenance.get(addr)
+    }
+    /// Per-source statistics, worst reachable ratio first. `nodes` is a copy
+    /// of the table from `nodes()`, so a caller that needs it for more than
+    /// this only takes one.
+    pub fn source_stats(&self, nodes: &[Node]) -> Vec<SourceStats> {
+        let states: HashMap<SocketAddr, &NodeState> =
+            nodes.iter().map(|node| (node.addr, &node.state)).collect();
+        let mut stats: HashMap<SocketAddr, SourceStats> = HashMap::new();
+        for (addr, provenance) in self.provenance.iter() {
+            let entry = stats.entry(provenance.source).or_insert(SourceStats {
+                source: provenance.source,
+                contributed: 0,
+                contacted: 0,
+                reachable: 0,
+            });
+            entry.contributed += 1;
+            match states.get(addr) {
+                Some(NodeState::Online) => {
+                    entry.contacted += 1;
+                    entry.reachable += 1;
+                }
+                Some(NodeState::Offline) => entry.contacted += 1,
+                _ => (),
+            }
         }
+        let mut stats: Vec<SourceStats> = stats.into_iter().map(|(_, s)| s).collect();
+        stats.sort_by(|a, b| {
+            a.reachable_ratio()
+                .partial_cmp(&b.reachable_ratio())
+                .unwrap()
+                .then(b.contributed.cmp(&a.contributed))
+        });
+        stats
     }
+    /// Sources that gave us at least `min_contacted` addresses we tried, of
+    /// which no more than `max_ratio` were reachable.
+    pub fn polluting_sources(
+        &self,
+        nodes: &[Node],
+        min_contacted: usize,
+        max_ratio: f64,
+    ) -> Vec<SourceStats> {
+        self.source_stats(nodes)
+            .into_iter()
+            .filter(|s| s.contacted >= min_contacted && s.reachable_ratio() <= max_ratio)
+            .collect()
+    }
+}
+
+/// Number of online nodes in `nodes` left out of DNS answers because they
+/// don't listen on `port`.
+pub fn count_online_on_other_ports(nodes: &[Node], port: u16) -> usize {
+    nodes
+        .iter()
+        .filter(|node| node.state == NodeState::Online && node.addr.port() != port)
+        .count()
 }
 
 #[cfg(test)]
@@ -95,16 +464,22 @@ mod tests {
             addr: "1.1.1.1:8888".parse().unwrap(),
             state: NodeState::Online,
             last_visit: SystemTime::now(),
+            version: None,
+            stats: NodeStats::default(),
         };
         let n2 = Node {
             addr: "2.2.2.2:8888".parse().unwrap(),
             state: NodeState::Online,
             last_visit: SystemTime::now(),
+            version: None,
+            stats: NodeStats::default(),
         };
         let n3 = Node {
             addr: "3.3.3.3:8888".parse().unwrap(),
             state: NodeState::Offline,
             last_visit: SystemTime::now(),
+            version: None,
+            stats: NodeStats::default(),
         };
 
         let mut db = NodeDb::new();
@@ -117,6 +492,24 @@ mod tests {
         assert_eq!(0, *report.get(&NodeState::Uncontacted).unwrap());
     }
 
+    #[test]
+    fn test_fetch_online_nodes_default_port() {
+        let mut db = NodeDb::new();
+        for addr in vec!["1.1.1.1:8333", "2.2.2.2:8333", "3.3.3.3:8334"] {
+            db.insert(Node {
+                addr: addr.parse().unwrap(),
+                state: NodeState::Online,
+                last_visit: SystemTime::now(),
+                version: None,
+                stats: NodeStats::default(),
+            });
+        }
+        let nodes = db.fetch_online_nodes(10, 8333);
+        assert_eq!(2, nodes.len());
+        assert!(nodes.iter().all(|node| node.addr.port() == 8333));
+        assert_eq!(1, db.count_online_on_other_ports(8333));
+    }
+
     #[test]
     fn test_next() {
         let mut db = NodeDb::new();
@@ -126,6 +519,8 @@ mod tests {
             addr: "123.123.123.123:8888".parse().unwrap(),
             state: NodeState::Online,
             last_visit: SystemTime::now(),
+            version: None,
+            stats: NodeStats::default(),
         };
         db.insert(n1);
         // n1 isn't due so None is our answer
@@ -136,6 +531,8 @@ mod tests {
             addr: "123.123.123.123:8888".parse().unwrap(),
             state: NodeState::Online,
             last_visit: SystemTime::now() - Duration::new(15 * 60, 0),
+            version: None,
+            stats: NodeStats::default(),
         };
         db.insert(n2.clone());
         // n2 is due, so is "next"
@@ -143,4 +540,124 @@ mod tests {
         assert_eq!(n2.addr, r.addr);
         assert!(r.last_visit > SystemTime::now() - Duration::new(1, 0));
     }
+
+    #[test]
+    fn test_next_oldest_first() {
+        let mut db = NodeDb::new();
+        let now = SystemTime::now();
+        for (addr, hours) in vec![
+            ("1.1.1.1:8333", 2),
+            ("2.2.2.2:8333", 5),
+            ("3.3.3.3:8333", 0),
+        ] {
+            db.insert(Node {
+                addr: addr.parse().unwrap(),
+                state: NodeState::Online,
+                last_visit: now - Duration::new(hours * 3600, 0),
+                version: None,
+                stats: NodeStats::default(),
+            });
+        }
+        assert_eq!("2.2.2.2:8333".parse::<SocketAddr>().unwrap(), db.next().unwrap().addr);
+        assert_eq!("1.1.1.1:8333".parse::<SocketAddr>().unwrap(), db.next().unwrap().addr);
+        // both just got their `last_visit` bumped, and 3.3.3.3 isn't due yet
+        assert_eq!(None, db.next());
+    }
+
+    #[test]
+    fn test_node_stats() {
+        let start = UNIX_EPOCH + Duration::new(1_000_000_000, 0);
+        let mut stats = NodeStats::default();
+        stats.record_visit(true, start);
+        // the first visit has no age, so it carries no weight yet
+        assert_eq!(0.0, stats.uptime[0].reliability);
+        assert_eq!(Some(start), stats.last_success);
+
+        // always up, hourly visits
+        for i in 1..200 {
+            stats.record_visit(true, start + Duration::new(i * 3600, 0));
+        }
+        assert!(stats.uptime[0].reliability > 0.99);
+        assert!(stats.uptime[4].reliability > 0.2);
+
+        // down ever since, the short window notices first
+        let down = start + Duration::new(200 * 3600, 0);
+        for i in 0..4 {
+            stats.record_visit(false, down + Duration::new(i * 3600, 0));
+        }
+        assert!(stats.uptime[0].reliability < 0.2);
+        assert!(stats.uptime[4].reliability > stats.uptime[0].reliability);
+        assert_eq!(Some(start + Duration::new(199 * 3600, 0)), stats.last_success);
+    }
+
+    #[test]
+    fn test_source_stats() {
+        let mut db = NodeDb::new();
+        let good_source: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let bad_source: SocketAddr = "10.0.0.2:8333".parse().unwrap();
+
+        let a1: SocketAddr = "1.1.1.1:8333".parse().unwrap();
+        let a2: SocketAddr = "2.2.2.2:8333".parse().unwrap();
+        let a3: SocketAddr = "3.3.3.3:8333".parse().unwrap();
+        let a4: SocketAddr = "4.4.4.4:8333".parse().unwrap();
+        db.init_from(a1, good_source);
+        db.init_all_from(&[a2, a3, a4], bad_source);
+        // a second gossip of a1 doesn't change who gets credit for it
+        db.init_from(a1, bad_source);
+        assert_eq!(good_source, db.provenance(&a1).unwrap().source);
+
+        for (addr, state) in vec![
+            (a1, NodeState::Online),
+            (a2, NodeState::Offline),
+            (a3, NodeState::Offline),
+        ] {
+            db.insert(Node {
+                addr,
+                state,
+                last_visit: SystemTime::now(),
+                version: None,
+                stats: NodeStats::default(),
+            });
+        }
+
+        let stats = db.source_stats(&db.nodes());
+        assert_eq!(2, stats.len());
+        assert_eq!(bad_source, stats[0].source);
+        assert_eq!(3, stats[0].contributed);
+        assert_eq!(2, stats[0].contacted);
+        assert_eq!(0, stats[0].reachable);
+        assert_eq!(1.0, stats[1].reachable_ratio());
+
+        let polluters = db.polluting_sources(&db.nodes(), 2, 0.1);
+        assert_eq!(1, polluters.len());
+        assert_eq!(bad_source, polluters[0].source);
+    }
+
+    #[test]
+    fn test_bans() {
+        let mut db = NodeDb::new();
+        db.bans_mut().add("ban 10.0.0.0/8".parse().unwrap());
+
+        db.init("10.1.1.1:8333".parse().unwrap());
+        db.init("1.1.1.1:8333".parse().unwrap());
+        assert_eq!(1, *db.report().get(&NodeState::Uncontacted).unwrap());
+        let source: SocketAddr = "2.2.2.2:8333".parse().unwrap();
+        let gossiped: SocketAddr = "10.2.2.2:8333".parse().unwrap();
+        db.init_all_from(&[gossiped], source);
+        assert_eq!(1, *db.report().get(&NodeState::Uncontacted).unwrap());
+        assert!(db.provenance(&gossiped).is_none());
+
+        // nodes banned after we learned about them are neither visited nor served
+        db.insert(Node {
+            addr: "11.1.1.1:8333".parse().unwrap(),
+            state: NodeState::Online,
+            last_visit: UNIX_EPOCH,
+            version: None,
+            stats: NodeStats::default(),
+        });
+        db.bans_mut().add("ban 11.1.1.1".parse().unwrap());
+        assert!(db.fetch_online_nodes(10, 8333).is_empty());
+        assert_eq!("1.1.1.1:8333".parse::<SocketAddr>().unwrap(), db.next().unwrap().addr);
+        assert_eq!(None, db.next());
+    }
 }
@@ -1,11 +1,34 @@
-use std::fs::File;
+//! A minimal DNS wire codec and the seed's DNS server.
+//!
+//! [`DnsPacket`] reads and writes messages through a [`BytePacketBuffer`].
+//! [`serve`] answers queries for the seed name from the precomputed
+//! [`AnswerCache`] and forwards everything else upstream with [`lookup`].
+
+use log::{error, info, trace};
+use serde::Deserialize;
+use net2::{TcpBuilder, UdpBuilder};
+use std::error::Error;
+use std::fmt;
 use std::io;
-use std::io::Read;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
-use std::sync::{Arc, Mutex};
-
-use super::db;
-
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::answers;
+use super::answers::AnswerCache;
+use super::cookie;
+use super::cookie::{CookieStatus, ServerCookies};
+use super::metrics::Metrics;
+use super::rrl::{Action, RateLimiter};
+use super::systemd;
+use super::systemd::SocketKind;
+
+/// A 4096 byte message buffer with a cursor.
 pub struct BytePacketBuffer {
     pub buf: [u8; 4096],
     pub pos: usize,
@@ -215,7 +238,7 @@ impl BytePacketBuffer {
 
     fn set_u16(&mut self, pos: usize, val: u16) -> Result<(), io::Error> {
         self.set(pos, (val >> 8) as u8)?;
-        self.set(pos, (val & 0xFF) as u8)?;
+        self.set(pos + 1, (val & 0xFF) as u8)?;
         Ok(())
     }
 }
@@ -228,6 +251,11 @@ pub enum ResultCode {
     NXDOMAIN = 3,
     NOTIMP = 4,
     REFUSED = 5,
+    YXDOMAIN = 6,
+    YXRRSET = 7,
+    NXRRSET = 8,
+    NOTAUTH = 9,
+    NOTZONE = 10,
 }
 
 impl ResultCode {
@@ -238,6 +266,11 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
             0 | _ => ResultCode::NOERROR,
         }
     }
@@ -349,6 +382,7 @@ pub enum QueryType {
     CNAME, // 5
     MX,    //15
     AAAA,  // 28
+    OPT,   // 41
 }
 
 impl QueryType {
@@ -360,6 +394,7 @@ impl QueryType {
             QueryType::CNAME => 5,
             QueryType::MX => 15,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
         }
     }
 
@@ -370,6 +405,7 @@ impl QueryType {
             5 => QueryType::CNAME,
             15 => QueryType::MX,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
             _ => QueryType::UNKNOWN(num),
         }
     }
@@ -404,6 +440,13 @@ impl DnsQuestion {
     }
 }
 
+/// An option in an EDNS `OPT` record (RFC 6891).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[allow(dead_code)]
 pub enum DnsRecord {
@@ -439,6 +482,13 @@ pub enum DnsRecord {
         addr: Ipv6Addr,
         ttl: u32,
     },
+    // EDNS pseudo-record: the class is the sender's UDP payload size and the
+    // TTL holds the extended rcode, version and flags
+    OPT {
+        packet_len: u16,
+        flags: u32,
+        options: Vec<EdnsOption>,
+    }, // 41
 }
 
 impl DnsRecord {
@@ -447,7 +497,7 @@ impl DnsRecord {
         buffer.read_qname(&mut domain);
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?; // class, which we ignore
+        let class = buffer.read_u16()?; // class, which we ignore (except for OPT)
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
@@ -521,6 +571,32 @@ impl DnsRecord {
                 })
             }
 
+            QueryType::OPT => {
+                let mut options = vec![];
+                let end = buffer.pos() + data_len as usize;
+                while buffer.pos() < end {
+                    let code = buffer.read_u16()?;
+                    let len = buffer.read_u16()?;
+                    let mut data = Vec::with_capacity(len as usize);
+                    for _ in 0..len {
+                        data.push(buffer.read()?);
+                    }
+                    options.push(EdnsOption { code, data });
+                }
+                if buffer.pos() != end {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "EDNS option overruns OPT record",
+                    ));
+                }
+
+                Ok(DnsRecord::OPT {
+                    packet_len: class,
+                    flags: ttl,
+                    options,
+                })
+            }
+
             QueryType::UNKNOWN(_) => {
                 buffer.step(data_len as usize)?;
 
@@ -624,14 +700,39 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                ref options,
+            } => {
+                buffer.write_u8(0)?; // root domain
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for option in options {
+                    buffer.write_u16(option.code)?;
+                    buffer.write_u16(option.data.len() as u16)?;
+                    for byte in &option.data {
+                        buffer.write_u8(*byte)?;
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+                trace!("Skipping record: {:?}", self);
             }
         }
         Ok(buffer.pos() - start_pos)
     }
 }
 
+/// A complete DNS message.
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -656,14 +757,12 @@ impl DnsPacket {
         let mut result = DnsPacket::new();
         result.header.read(buffer)?;
 
-        println!("{:?}", result.header);
         for _ in 0..result.header.questions {
             let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
             question.read(buffer)?;
             result.questions.push(question);
         }
 
-        println!("1");
         for _ in 0..result.header.answers {
             let rec = DnsRecord::read(buffer)?;
             result.answers.push(rec);
@@ -682,6 +781,23 @@ impl DnsPacket {
         Ok(result)
     }
 
+    /// Options of the EDNS `OPT` record, if the packet has one.
+    pub fn edns_options(&self) -> Option<&Vec<EdnsOption>> {
+        self.resources.iter().find_map(|record| match record {
+            DnsRecord::OPT { options, .. } => Some(options),
+            _ => None,
+        })
+    }
+
+    /// The full response code, including the upper bits from EDNS.
+    pub fn extended_rcode(&self) -> u16 {
+        let upper = self.resources.iter().find_map(|record| match record {
+            DnsRecord::OPT { flags, .. } => Some((flags >> 24) as u16),
+            _ => None,
+        });
+        upper.unwrap_or(0) << 4 | self.header.rescode as u16
+    }
+
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), io::Error> {
         self.header.questions = self.questions.len() as u16;
         self.header.answers = self.answers.len() as u16;
@@ -707,168 +823,1012 @@ impl DnsPacket {
     }
 }
 
-fn lookup(qname: &str, qtype: QueryType, server: (&str, u16)) -> Result<DnsPacket, io::Error> {
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+/// Why [`lookup`] didn't produce a response.
+#[derive(Debug)]
+pub enum LookupError {
+    Io(io::Error),
+    // nothing arrived before the deadline
+    Timeout,
+    // packets arrived, but none from the server with our ID and question
+    Mismatch(String),
+}
 
-    let mut packet = DnsPacket::new();
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LookupError::Io(e) => write!(f, "{}", e),
+            LookupError::Timeout => write!(f, "timed out"),
+            LookupError::Mismatch(reason) => write!(f, "no matching response: {}", reason),
+        }
+    }
+}
+
+impl Error for LookupError {}
+
+impl From<io::Error> for LookupError {
+    fn from(error: io::Error) -> Self {
+        LookupError::Io(error)
+    }
+}
 
-    packet.header.id = 6666;
-    packet.header.questions = 1;
+/// Ask `server` about `qname`, sending the query up to `attempts` times. Every
+/// attempt uses a fresh socket on a random ephemeral port and a random ID, and
+/// only a response from `server` echoing that ID and our question is accepted,
+/// so concurrent lookups don't collide and blind spoofing is impractical.
+pub fn lookup(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    timeout: Duration,
+    attempts: usize,
+) -> Result<DnsPacket, LookupError> {
+    let mut last_err = LookupError::Timeout;
+    for _ in 0..attempts {
+        match lookup_once(qname, qtype, server, timeout) {
+            Ok(packet) => return Ok(packet),
+            Err(LookupError::Io(e)) => return Err(LookupError::Io(e)),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+fn lookup_once(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<DnsPacket, LookupError> {
+    let bind: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind)?;
+
+    let question = DnsQuestion::new(qname.to_lowercase(), qtype);
+    let mut packet = DnsPacket::new();
+    packet.header.id = rand::random();
     packet.header.recursion_desired = true;
-    packet
-        .questions
-        .push(DnsQuestion::new(qname.to_string(), qtype));
+    packet.questions.push(question.clone());
 
     let mut req_buffer = BytePacketBuffer::new();
     packet.write(&mut req_buffer)?;
     socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
 
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buf)?;
+    let deadline = Instant::now() + timeout;
+    let mut mismatch = None;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(mismatch.map_or(LookupError::Timeout, LookupError::Mismatch));
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let mut res_buffer = BytePacketBuffer::new();
+        let (_, src) = match socket.recv_from(&mut res_buffer.buf) {
+            Ok(x) => x,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue;
+            }
+            Err(e) => return Err(LookupError::Io(e)),
+        };
+        if src != server {
+            mismatch = Some(format!("response from unexpected source {}", src));
+            continue;
+        }
+        let response = match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(response) => response,
+            Err(e) => {
+                mismatch = Some(format!("malformed response: {}", e));
+                continue;
+            }
+        };
+        if !response.header.response || response.header.id != packet.header.id {
+            mismatch = Some(format!("unexpected ID {}", response.header.id));
+            continue;
+        }
+        if response.questions.len() != 1 || response.questions[0] != question {
+            mismatch = Some(String::from("response is for a different question"));
+            continue;
+        }
+        return Ok(response);
+    }
+}
 
-    DnsPacket::from_buffer(&mut res_buffer)
+const SEED_NAME: &str = "seed.justinmoon.com";
+// peter wuille was sending this so i copied it
+const SEED_TTL: u32 = 3094;
+// more records than this may not fit in our 4096 byte buffer
+pub const MAX_RECORDS: usize = 64;
+
+/// Settings for the answers to seed queries, the `[dns]` table of the config.
+/// They can be changed while the server runs.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    pub ttl: u32,
+    // addresses per response, at most `MAX_RECORDS`
+    pub max_a_records: usize,
+    pub max_aaaa_records: usize,
 }
 
-fn parse() {
-    let mut f = File::open("response_packet.txt").unwrap();
-    let mut buffer = BytePacketBuffer::new();
-    f.read(&mut buffer.buf).unwrap();
+impl Default for DnsConfig {
+    fn default() -> DnsConfig {
+        DnsConfig {
+            ttl: SEED_TTL,
+            max_a_records: answers::MAX_A_RECORDS,
+            max_aaaa_records: answers::MAX_AAAA_RECORDS,
+        }
+    }
+}
 
-    let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
-    println!("{:?}", packet.header);
+// threads blocked in `recv_from` on each UDP socket
+const RECEIVE_THREADS: usize = 4;
+// upstream lookups run on their own threads so a slow forward never holds up
+// seed answers, and at most this many wait for one before we give up on them
+const FORWARD_THREADS: usize = 8;
+const FORWARD_QUEUE: usize = 64;
+// each TCP connection gets a thread, up to this many at once per listener
+const TCP_CONNECTIONS: usize = 64;
+// idle TCP connections are closed after this long
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+// how often threads waiting for queries check whether to stop
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The sockets the DNS server answers on, bound before [`serve`] so the
+/// caller can give up privileges in between.
+pub struct Listeners {
+    pub udp: Vec<UdpSocket>,
+    pub tcp: Vec<TcpListener>,
+}
 
-    for q in packet.questions {
-        println!("{:?}", q);
+impl Listeners {
+    /// Bind a UDP socket and a TCP listener on each of `addrs`. IPv6 sockets
+    /// only accept IPv6, so `0.0.0.0:53` and `[::]:53` can be used together.
+    pub fn bind(addrs: &[SocketAddr]) -> Result<Listeners, io::Error> {
+        let mut listeners = Listeners {
+            udp: vec![],
+            tcp: vec![],
+        };
+        for addr in addrs {
+            let context = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", addr, e));
+            listeners.udp.push(bind_udp(addr).map_err(context)?);
+            listeners.tcp.push(bind_tcp(addr).map_err(context)?);
+        }
+        Ok(listeners)
     }
-    for rec in packet.answers {
-        println!("{:?}", rec);
+
+    /// Take over the sockets passed in by systemd socket activation
+    /// (`LISTEN_FDS`), or `None` if there weren't any.
+    pub fn from_systemd() -> Result<Option<Listeners>, io::Error> {
+        let fds = systemd::listen_fds()?;
+        if fds.is_empty() {
+            return Ok(None);
+        }
+        // they're ours alone now that the environment has been cleared
+        unsafe { Listeners::from_fds(&fds).map(Some) }
     }
-    for rec in packet.authorities {
-        println!("{:?}", rec);
+
+    // wrap bound sockets we own, telling UDP from TCP by their type
+    unsafe fn from_fds(fds: &[RawFd]) -> Result<Listeners, io::Error> {
+        let mut listeners = Listeners {
+            udp: vec![],
+            tcp: vec![],
+        };
+        for fd in fds {
+            match systemd::socket_kind(*fd)? {
+                SocketKind::Datagram => listeners.udp.push(UdpSocket::from_raw_fd(*fd)),
+                SocketKind::Stream => listeners.tcp.push(TcpListener::from_raw_fd(*fd)),
+            }
+        }
+        Ok(listeners)
     }
-    for rec in packet.resources {
-        println!("{:?}", rec);
+}
+
+fn bind_udp(addr: &SocketAddr) -> Result<UdpSocket, io::Error> {
+    match addr {
+        SocketAddr::V4(_) => UdpBuilder::new_v4()?.bind(addr),
+        SocketAddr::V6(_) => UdpBuilder::new_v6()?.only_v6(true)?.bind(addr),
     }
 }
 
-pub fn serve(tdb: Arc<Mutex<db::NodeDb>>) {
-    // Bind UDP socket on port 2053
-    let socket = UdpSocket::bind(("0.0.0.0", 53)).unwrap();
+fn bind_tcp(addr: &SocketAddr) -> Result<TcpListener, io::Error> {
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    builder.reuse_address(true)?.bind(addr)?.listen(128)
+}
 
-    // Handle queries sequentially in a loop
-    loop {
+// what every listener needs to answer a query
+struct Handler {
+    answers: Arc<AnswerCache>,
+    rrl: Arc<RateLimiter>,
+    cookies: Arc<ServerCookies>,
+    config: Arc<RwLock<DnsConfig>>,
+    metrics: Arc<Metrics>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Handler {
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+}
+
+enum Reply {
+    Send(DnsPacket),
+    // not for us, look it up upstream first
+    Forward(DnsPacket),
+    Drop,
+}
+
+struct ForwardJob {
+    response: DnsPacket,
+    src: SocketAddr,
+    socket: Arc<UdpSocket>,
+}
+
+// a response to `request` with no answers yet
+fn new_response(request: &DnsPacket) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.recursion_desired = true;
+    packet.header.recursion_available = true;
+    packet.header.response = true;
+    packet.header.rescode = ResultCode::NOERROR;
+    packet.questions = request.questions.iter().take(1).cloned().collect();
+    packet
+}
+
+fn answer_seed(packet: &mut DnsPacket, filter: u64, answers: &AnswerCache, config: &DnsConfig) {
+    let question = packet.questions[0].clone();
+    let sets = answers.get();
+    match question.qtype {
+        QueryType::A => {
+            for ip in sets.pick_v4(filter, config.max_a_records) {
+                packet.answers.push(DnsRecord::A {
+                    domain: question.name.clone(),
+                    addr: ip,
+                    ttl: config.ttl,
+                });
+            }
+        }
+        QueryType::AAAA => {
+            for ip in sets.pick_v6(filter, config.max_aaaa_records) {
+                packet.answers.push(DnsRecord::AAAA {
+                    domain: question.name.clone(),
+                    addr: ip,
+                    ttl: config.ttl,
+                });
+            }
+        }
+        _ => (),
+    }
+}
+
+// the most a UDP response to `request` may take: whatever the client
+// advertised with EDNS, up to what we're willing to send, or 512 bytes
+fn udp_payload_limit(request: &DnsPacket) -> usize {
+    let advertised = request.resources.iter().find_map(|record| match record {
+        DnsRecord::OPT { packet_len, .. } => Some(*packet_len),
+        _ => None,
+    });
+    match advertised {
+        Some(len) => len.max(512).min(cookie::UDP_PAYLOAD_SIZE) as usize,
+        None => 512,
+    }
+}
+
+// drop answers from the end until `packet` fits in `limit` bytes, marking it
+// truncated if any had to go so the client can retry over TCP
+fn truncate_answers(packet: &mut DnsPacket, limit: usize) -> Result<(), io::Error> {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer)?;
+    let mut size = buffer.pos();
+    while size > limit {
+        let record = match packet.answers.pop() {
+            Some(record) => record,
+            None => break,
+        };
+        size -= record.write(&mut BytePacketBuffer::new())?;
+        packet.header.truncated_message = true;
+    }
+    Ok(())
+}
+
+fn forward(packet: &mut DnsPacket) {
+    let question = packet.questions[0].clone();
+    // Forward queries to Google's public DNS
+    let server = "8.8.8.8:53".parse().unwrap();
+
+    match lookup(
+        &question.name,
+        question.qtype,
+        server,
+        Duration::from_secs(2),
+        2,
+    ) {
+        Ok(result) => {
+            trace!("lookup for {} succeeded", question.name);
+            packet.header.rescode = result.header.rescode;
+            packet.answers.extend(result.answers);
+            packet.authorities.extend(result.authorities);
+            packet.resources.extend(result.resources);
+        }
+        Err(e) => {
+            // If lookup failed, set `SERVFAIL` response code
+            trace!("lookup for {} failed: {}", question.name, e);
+            packet.header.rescode = ResultCode::SERVFAIL;
+        }
+    }
+}
+
+impl Handler {
+    // the reply to `request` from `src`, rate limited unless it came over
+    // TCP, which can't be spoofed
+    fn handle(&self, request: &DnsPacket, src: SocketAddr, udp: bool) -> Reply {
+        let mut packet = new_response(request);
+        let now = SystemTime::now();
+        let cookie = self.cookies.check(request, src.ip(), now);
+        if let Some(opt) = self.cookies.response_opt(request, &cookie, src.ip(), now) {
+            packet.resources.push(opt);
+        }
+        // a valid server cookie proves the source address isn't spoofed
+        let action = match cookie {
+            CookieStatus::Valid(_) => Action::Send,
+            _ if !udp => Action::Send,
+            _ => self.rrl.check(src.ip(), Instant::now()),
+        };
+        match action {
+            Action::Send => (),
+            Action::Drop => {
+                self.metrics.dns_rrl.inc(&["dropped"]);
+                return Reply::Drop;
+            }
+            Action::Slip => {
+                // empty and truncated, a real resolver will retry over TCP
+                self.metrics.dns_rrl.inc(&["slipped"]);
+                packet.header.truncated_message = true;
+                return Reply::Send(packet);
+            }
+        }
+        if packet.questions.is_empty() || cookie == CookieStatus::Malformed {
+            packet.header.rescode = ResultCode::FORMERR;
+            return Reply::Send(packet);
+        }
+        if let CookieStatus::Bad(_) = cookie {
+            // the response carries a fresh cookie for the client to retry with
+            cookie::set_extended_rcode(&mut packet, cookie::BADCOOKIE);
+            return Reply::Send(packet);
+        }
+
+        match answers::service_filter(&packet.questions[0].name, SEED_NAME) {
+            Some(filter) => {
+                let config = self.config.read().unwrap().clone();
+                answer_seed(&mut packet, filter, &self.answers, &config);
+                if udp {
+                    if let Err(e) = truncate_answers(&mut packet, udp_payload_limit(request)) {
+                        error!("Failed to encode response packet: {:?}", e);
+                        return Reply::Drop;
+                    }
+                }
+                Reply::Send(packet)
+            }
+            None => Reply::Forward(packet),
+        }
+    }
+}
+
+// the `qtype` metrics label; types we don't know share one, clients pick
+// the number and would otherwise get a new time series each
+fn qtype_label(qtype: QueryType) -> &'static str {
+    match qtype {
+        QueryType::A => "A",
+        QueryType::NS => "NS",
+        QueryType::CNAME => "CNAME",
+        QueryType::MX => "MX",
+        QueryType::AAAA => "AAAA",
+        QueryType::OPT => "OPT",
+        QueryType::UNKNOWN(_) => "other",
+    }
+}
+
+// record `packet` in the metrics and encode it
+fn encode(packet: &mut DnsPacket, metrics: &Metrics) -> Option<BytePacketBuffer> {
+    let qtype = packet
+        .questions
+        .first()
+        .map_or("none", |q| qtype_label(q.qtype));
+    let rcode = match packet.extended_rcode() {
+        cookie::BADCOOKIE => String::from("BADCOOKIE"),
+        _ => format!("{:?}", packet.header.rescode),
+    };
+    metrics.dns_queries.inc(&[qtype, &rcode]);
+    metrics
+        .dns_answer_records
+        .observe(packet.answers.len() as u64);
+
+    let mut res_buffer = BytePacketBuffer::new();
+    if let Err(e) = packet.write(&mut res_buffer) {
+        error!("Failed to encode response packet: {:?}", e);
+        return None;
+    }
+    Some(res_buffer)
+}
+
+fn send(socket: &UdpSocket, mut packet: DnsPacket, dst: SocketAddr, metrics: &Metrics) {
+    let res_buffer = match encode(&mut packet, metrics) {
+        Some(x) => x,
+        None => return,
+    };
+    if let Err(e) = socket.send_to(&res_buffer.buf[0..res_buffer.pos], dst) {
+        trace!("Failed to send response to {}: {:?}", dst, e);
+    }
+}
+
+fn receive_loop(socket: Arc<UdpSocket>, handler: Arc<Handler>, forwards: SyncSender<ForwardJob>) {
+    let metrics = &handler.metrics;
+    while !handler.stopped() {
         let mut req_buffer = BytePacketBuffer::new();
 
         // Block until we receive a packet, jump to next iteration if read fails
         // Keep src b/c we'll use it to send our response
         let (_, src) = match socket.recv_from(&mut req_buffer.buf) {
             Ok(x) => x,
+            // the read timeout, time to check `stop`
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
             Err(e) => {
-                println!("Failed to read from UDP socket: {:?}", e);
+                error!("Failed to read from UDP socket: {:?}", e);
                 continue;
             }
         };
 
         // Convert BytePacketBuffer into DnsPacket, jump to next iteration if it fails
         let request = match DnsPacket::from_buffer(&mut req_buffer) {
-            Ok(x) => {
-                println!("Received DnsPacket: {:?}", x);
-                x
-            }
+            Ok(x) => x,
             Err(e) => {
-                println!("Failed to parse UDP query packet: {:?}", e);
+                trace!("Failed to parse UDP query packet from {}: {:?}", src, e);
+                metrics.dns_malformed.inc();
                 continue;
             }
         };
+        trace!("Received query from {}: {:?}", src, request.questions);
+
+        match handler.handle(&request, src, true) {
+            Reply::Send(packet) => send(&socket, packet, src, metrics),
+            Reply::Forward(packet) => {
+                let job = ForwardJob {
+                    response: packet,
+                    src,
+                    socket: socket.clone(),
+                };
+                // every forward thread is busy and the queue is full, fail
+                // fast instead of letting clients pile up behind upstream
+                if let Err(TrySendError::Full(mut job)) = forwards.try_send(job) {
+                    metrics.dns_forwards_dropped.inc();
+                    job.response.header.rescode = ResultCode::SERVFAIL;
+                    send(&socket, job.response, job.src, metrics);
+                }
+            }
+            Reply::Drop => (),
+        }
+    }
+}
 
-        // Create and initialize the response packet
-        let mut packet = DnsPacket::new();
-        packet.header.id = request.header.id;
-        packet.header.recursion_desired = true;
-        packet.header.recursion_available = true;
-        packet.header.response = true;
+fn forward_loop(jobs: Arc<Mutex<Receiver<ForwardJob>>>, metrics: Arc<Metrics>) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let mut response = job.response;
+        forward(&mut response);
+        send(&job.socket, response, job.src, &metrics);
+    }
+}
 
-        if request.questions.is_empty() {
-            packet.header.rescode = ResultCode::FORMERR;
-        } else {
-            packet.header.rescode = ResultCode::NOERROR;
+// answer length-prefixed queries on `stream` until the client closes it or
+// goes quiet
+fn serve_tcp_connection(mut stream: TcpStream, handler: &Handler) -> Result<(), io::Error> {
+    let src = stream.peer_addr()?;
+    stream.set_read_timeout(Some(TCP_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+    while !handler.stopped() {
+        let mut len = [0u8; 2];
+        match stream.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
         }
+        let len = (usize::from(len[0]) << 8) | usize::from(len[1]);
+        let mut req_buffer = BytePacketBuffer::new();
+        if len > req_buffer.buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "query larger than our buffer",
+            ));
+        }
+        stream.read_exact(&mut req_buffer.buf[0..len])?;
+        let request = match DnsPacket::from_buffer(&mut req_buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                handler.metrics.dns_malformed.inc();
+                return Err(e);
+            }
+        };
+        trace!("Received TCP query from {}: {:?}", src, request.questions);
+
+        let mut packet = match handler.handle(&request, src, false) {
+            Reply::Send(packet) => packet,
+            // this connection has its own thread, so it can wait for upstream
+            Reply::Forward(mut packet) => {
+                forward(&mut packet);
+                packet
+            }
+            Reply::Drop => continue,
+        };
+        let res_buffer = match encode(&mut packet, &handler.metrics) {
+            Some(x) => x,
+            None => continue,
+        };
+        let mut message = Vec::with_capacity(res_buffer.pos + 2);
+        message.push((res_buffer.pos >> 8) as u8);
+        message.push((res_buffer.pos & 0xFF) as u8);
+        message.extend_from_slice(&res_buffer.buf[0..res_buffer.pos]);
+        stream.write_all(&message)?;
+    }
+    Ok(())
+}
 
-        let question = &request.questions[0];
-        packet.questions.push(question.clone());
-        println!("Received query: {:?}", question);
-
-        if question.name == "seed.justinmoon.com" {
-            // Lookup nodes in db and assemble response
-            let nodes = tdb.lock().unwrap().fetch_online_nodes(25);
+// `listener` is non-blocking, so we get to check `stop` in between
+fn accept_loop(listener: TcpListener, handler: Arc<Handler>) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    while !handler.stopped() {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(STOP_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to accept TCP connection: {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_nonblocking(false) {
+            error!("Failed to set up TCP connection: {:?}", e);
+            continue;
+        }
+        if connections.fetch_add(1, Ordering::SeqCst) >= TCP_CONNECTIONS {
+            // dropping the stream closes it
+            connections.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        }
+        let handler = handler.clone();
+        let connections = connections.clone();
+        let spawned = thread::Builder::new()
+            .name(String::from("dns-tcp"))
+            .spawn(move || {
+                if let Err(e) = serve_tcp_connection(stream, &handler) {
+                    trace!("TCP connection closed: {:?}", e);
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        if let Err(e) = spawned {
+            error!("Couldn't spawn DNS TCP thread: {:?}", e);
+        }
+    }
+}
 
-            for node in nodes {
-                // DnsRecord needs Ipv4Addr, this extracts it from node's SocketAddr
-                let ip = match node.addr.ip() {
-                    IpAddr::V4(ip4) => ip4,
-                    _ => panic!("can't handle ipv6"),
-                };
+/// Answer DNS queries on every socket in `listeners`, for the seed name (and
+/// its `x<hex>` service subdomains) from `answers`. Each UDP socket is read
+/// by several threads, each TCP connection gets its own, and everything else
+/// is forwarded upstream from a bounded pool of threads. UDP responses are
+/// rate limited by `rrl`, except to clients presenting a valid DNS cookie
+/// from `cookies`. Answer sizes and TTLs are read from `config` for every
+/// query. Returns once every thread has been started; they stop listening
+/// soon after `stop` is set.
+pub fn serve(
+    listeners: Listeners,
+    answers: Arc<AnswerCache>,
+    rrl: Arc<RateLimiter>,
+    cookies: Arc<ServerCookies>,
+    config: Arc<RwLock<DnsConfig>>,
+    metrics: Arc<Metrics>,
+    stop: Arc<AtomicBool>,
+) {
+    let handler = Arc::new(Handler {
+        answers,
+        rrl,
+        cookies,
+        config,
+        metrics,
+        stop,
+    });
+    let (tx, rx) = mpsc::sync_channel(FORWARD_QUEUE);
+    let rx = Arc::new(Mutex::new(rx));
+    for i in 0..FORWARD_THREADS {
+        let rx = rx.clone();
+        let metrics = handler.metrics.clone();
+        thread::Builder::new()
+            .name(format!("dns-forward-{}", i))
+            .spawn(move || forward_loop(rx, metrics))
+            .expect("Couldn't spawn DNS forward thread");
+    }
+    for socket in listeners.udp {
+        if let Err(e) = socket.set_read_timeout(Some(STOP_POLL_INTERVAL)) {
+            error!("Failed to set UDP read timeout: {:?}", e);
+        }
+        let socket = Arc::new(socket);
+        match socket.local_addr() {
+            Ok(addr) => info!("Listening for DNS on udp {}", addr),
+            Err(e) => error!("Failed to get local address: {:?}", e),
+        }
+        for i in 0..RECEIVE_THREADS {
+            let socket = socket.clone();
+            let handler = handler.clone();
+            let tx = tx.clone();
+            thread::Builder::new()
+                .name(format!("dns-{}", i))
+                .spawn(move || receive_loop(socket, handler, tx))
+                .expect("Couldn't spawn DNS thread");
+        }
+    }
+    for listener in listeners.tcp {
+        if let Err(e) = listener.set_nonblocking(true) {
+            error!("Failed to make TCP listener non-blocking: {:?}", e);
+        }
+        match listener.local_addr() {
+            Ok(addr) => info!("Listening for DNS on tcp {}", addr),
+            Err(e) => error!("Failed to get local address: {:?}", e),
+        }
+        let handler = handler.clone();
+        thread::Builder::new()
+            .name(String::from("dns-accept"))
+            .spawn(move || accept_loop(listener, handler))
+            .expect("Couldn't spawn DNS accept thread");
+    }
+}
 
-                packet.answers.push(DnsRecord::A {
-                    domain: String::from(question.name.clone()), // FIXME
-                    addr: ip,
-                    ttl: 3094, // peter wuille was sending this so i copied it
-                });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rrl::RrlConfig;
+
+    // answers the first query with the wrong ID, then (if `genuine`) correctly
+    fn fake_server(genuine: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut req_buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            let mut response = DnsPacket::new();
+            response.header.response = true;
+            response.questions = request.questions.clone();
+            response.answers.push(DnsRecord::A {
+                domain: request.questions[0].name.clone(),
+                addr: Ipv4Addr::new(6, 6, 6, 6),
+                ttl: 60,
+            });
+            response.header.id = request.header.id.wrapping_add(1);
+            let mut spoofed = BytePacketBuffer::new();
+            response.write(&mut spoofed).unwrap();
+            socket.send_to(&spoofed.buf[0..spoofed.pos], src).unwrap();
+            if !genuine {
+                return;
             }
-        } else {
-            // Forward queries to Google's public DNS
-            let server = ("8.8.8.8", 53);
 
-            if let Ok(result) = lookup(&question.name, question.qtype, server) {
-                println!("lookup succeeded");
-                packet.header.rescode = result.header.rescode;
+            response.header.id = request.header.id;
+            response.answers = vec![DnsRecord::A {
+                domain: request.questions[0].name.clone(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 60,
+            }];
+            let mut genuine = BytePacketBuffer::new();
+            response.write(&mut genuine).unwrap();
+            socket.send_to(&genuine.buf[0..genuine.pos], src).unwrap();
+        });
+        addr
+    }
 
-                for rec in result.answers {
-                    println!("Answer: {:?}", rec);
-                    packet.answers.push(rec);
-                }
+    #[test]
+    fn test_lookup_ignores_mismatched_id() {
+        let server = fake_server(true);
+        let packet = lookup(
+            "Seed.Example.com",
+            QueryType::A,
+            server,
+            Duration::from_secs(2),
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![DnsRecord::A {
+                domain: String::from("seed.example.com"),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 60,
+            }],
+            packet.answers
+        );
+    }
 
-                for rec in result.authorities {
-                    println!("Authority: {:?}", rec);
-                    packet.authorities.push(rec);
-                }
+    // a server on loopback, returning its UDP and TCP addresses
+    fn start_server(answers: Arc<AnswerCache>, rrl: RrlConfig) -> (SocketAddr, SocketAddr) {
+        let listeners = Listeners::bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let udp = listeners.udp[0].local_addr().unwrap();
+        let tcp = listeners.tcp[0].local_addr().unwrap();
+        let rrl = Arc::new(RateLimiter::new(rrl));
+        let cookies = Arc::new(ServerCookies::new(true));
+        let config = Arc::new(RwLock::new(DnsConfig::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let metrics = Arc::new(Metrics::new());
+        serve(listeners, answers, rrl, cookies, config, metrics, stop);
+        (udp, tcp)
+    }
 
-                for rec in result.resources {
-                    println!("Resource: {:?}", rec);
-                    packet.resources.push(rec);
-                }
-            } else {
-                // If lookup failed, set `SERVFAIL` response code
-                println!("lookup failed");
-                packet.header.rescode = ResultCode::SERVFAIL;
-            }
+    #[test]
+    fn test_truncate_answers() {
+        let mut query = DnsPacket::new();
+        query
+            .questions
+            .push(DnsQuestion::new(String::from(SEED_NAME), QueryType::A));
+        assert_eq!(512, udp_payload_limit(&query));
+        let mut response = new_response(&query);
+        for i in 0..25 {
+            response.answers.push(DnsRecord::A {
+                domain: String::from(SEED_NAME),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: SEED_TTL,
+            });
         }
 
-        // Encode and send our response
-        let mut res_buffer = BytePacketBuffer::new();
-        match packet.write(&mut res_buffer) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("Failed to encode UDP response packet: {:?}", e);
-                continue;
-            }
+        let mut small = response.clone();
+        truncate_answers(&mut small, udp_payload_limit(&query)).unwrap();
+        assert!(small.header.truncated_message);
+        assert_eq!(13, small.answers.len());
+        let mut buffer = BytePacketBuffer::new();
+        small.write(&mut buffer).unwrap();
+        assert!(buffer.pos() <= 512);
+
+        // with EDNS there's room for all of them, but never more than we'd send
+        query.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            options: vec![],
+        });
+        assert_eq!(1232, udp_payload_limit(&query));
+        let mut large = response.clone();
+        truncate_answers(&mut large, udp_payload_limit(&query)).unwrap();
+        assert!(!large.header.truncated_message);
+        assert_eq!(25, large.answers.len());
+    }
+
+    #[test]
+    fn test_qtype_label() {
+        assert_eq!("AAAA", qtype_label(QueryType::AAAA));
+        assert_eq!("other", qtype_label(QueryType::UNKNOWN(1234)));
+        assert_eq!("other", qtype_label(QueryType::UNKNOWN(65535)));
+    }
+
+    #[test]
+    fn test_listeners_from_fds() {
+        use std::os::unix::io::IntoRawFd;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = (udp.local_addr().unwrap(), tcp.local_addr().unwrap());
+        let fds = [tcp.into_raw_fd(), udp.into_raw_fd()];
+        let listeners = unsafe { Listeners::from_fds(&fds).unwrap() };
+        assert_eq!(1, listeners.udp.len());
+        assert_eq!(1, listeners.tcp.len());
+        assert_eq!(addrs.0, listeners.udp[0].local_addr().unwrap());
+        assert_eq!(addrs.1, listeners.tcp[0].local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_serve_seed_queries() {
+        let mut db = crate::db::NodeDb::new();
+        db.insert(crate::db::Node {
+            addr: "1.2.3.4:8333".parse().unwrap(),
+            state: crate::db::NodeState::Online,
+            last_visit: std::time::SystemTime::now(),
+            version: None,
+            stats: crate::db::NodeStats::default(),
+        });
+        let answers = Arc::new(AnswerCache::new());
+        answers.refresh(&db, 8333);
+        let (server, _) = start_server(answers, RrlConfig::default());
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let exchange = |mut query: DnsPacket| {
+            let mut buffer = BytePacketBuffer::new();
+            query.write(&mut buffer).unwrap();
+            client.send_to(&buffer.buf[0..buffer.pos], server).unwrap();
+            let mut buffer = BytePacketBuffer::new();
+            client.recv_from(&mut buffer.buf).unwrap();
+            DnsPacket::from_buffer(&mut buffer).unwrap()
         };
 
-        let len = res_buffer.pos();
-        let data = match res_buffer.get_range(0, len) {
-            Ok(x) => x,
-            Err(e) => {
-                println!("Failed to retrieve response buffer: {:?}", e);
-                continue;
-            }
+        // no question at all, which used to take the server down
+        let response = exchange(DnsPacket::new());
+        assert_eq!(ResultCode::FORMERR, response.header.rescode);
+
+        let mut query = DnsPacket::new();
+        query.header.id = 42;
+        query
+            .questions
+            .push(DnsQuestion::new(String::from(SEED_NAME), QueryType::A));
+        let response = exchange(query);
+        assert_eq!(42, response.header.id);
+        assert_eq!(ResultCode::NOERROR, response.header.rescode);
+        assert_eq!(
+            vec![DnsRecord::A {
+                domain: String::from(SEED_NAME),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: SEED_TTL,
+            }],
+            response.answers
+        );
+    }
+
+    #[test]
+    fn test_serve_slips_over_limit() {
+        let rrl = RrlConfig {
+            responses_per_second: 1,
+            slip: 1,
+            ..RrlConfig::default()
         };
+        let (server, _) = start_server(Arc::new(AnswerCache::new()), rrl);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut query = DnsPacket::new();
+        query
+            .questions
+            .push(DnsQuestion::new(String::from(SEED_NAME), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        query.write(&mut buffer).unwrap();
+        let mut truncated = vec![];
+        for _ in 0..2 {
+            client.send_to(&buffer.buf[0..buffer.pos], server).unwrap();
+            let mut response = BytePacketBuffer::new();
+            client.recv_from(&mut response.buf).unwrap();
+            let response = DnsPacket::from_buffer(&mut response).unwrap();
+            truncated.push(response.header.truncated_message);
+        }
+        assert_eq!(vec![false, true], truncated);
+    }
 
-        match socket.send_to(data, src) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("Failed to send response buffer: {:?}", e);
-                continue;
-            }
+    #[test]
+    fn test_serve_valid_cookie_skips_limit() {
+        let rrl = RrlConfig {
+            responses_per_second: 1,
+            slip: 1,
+            ..RrlConfig::default()
+        };
+        let (server, _) = start_server(Arc::new(AnswerCache::new()), rrl);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut cookie = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        for i in 0..3 {
+            let mut query = DnsPacket::new();
+            query
+                .questions
+                .push(DnsQuestion::new(String::from(SEED_NAME), QueryType::A));
+            query.resources.push(DnsRecord::OPT {
+                packet_len: 1232,
+                flags: 0,
+                options: vec![EdnsOption {
+                    code: cookie::COOKIE_OPTION,
+                    data: cookie.clone(),
+                }],
+            });
+            let mut buffer = BytePacketBuffer::new();
+            query.write(&mut buffer).unwrap();
+            client.send_to(&buffer.buf[0..buffer.pos], server).unwrap();
+            let mut response = BytePacketBuffer::new();
+            client.recv_from(&mut response.buf).unwrap();
+            let response = DnsPacket::from_buffer(&mut response).unwrap();
+            // only the first query, without a server cookie, counts against the limit
+            assert!(!response.header.truncated_message, "query {}", i);
+            let option = response
+                .edns_options()
+                .unwrap()
+                .iter()
+                .find(|o| o.code == cookie::COOKIE_OPTION)
+                .unwrap();
+            assert_eq!(&cookie[0..8], &option.data[0..8]);
+            cookie = option.data.clone();
+        }
+    }
+
+    #[test]
+    fn test_lookup_mismatch_error() {
+        let server = fake_server(false);
+        let timeout = Duration::from_millis(200);
+        match lookup("seed.example.com", QueryType::A, server, timeout, 1) {
+            Err(LookupError::Mismatch(_)) => (),
+            other => panic!("expected a mismatch, got {:?}", other),
         }
     }
+
+    #[test]
+    fn test_serve_tcp() {
+        // TCP isn't rate limited, it can't be spoofed
+        let rrl = RrlConfig {
+            responses_per_second: 1,
+            slip: 1,
+            ..RrlConfig::default()
+        };
+        let (_, server) = start_server(Arc::new(AnswerCache::new()), rrl);
+        let mut stream = TcpStream::connect(server).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        for id in 0..3 {
+            let mut query = DnsPacket::new();
+            query.header.id = id;
+            query
+                .questions
+                .push(DnsQuestion::new(String::from(SEED_NAME), QueryType::A));
+            let mut buffer = BytePacketBuffer::new();
+            query.write(&mut buffer).unwrap();
+            stream
+                .write_all(&[(buffer.pos >> 8) as u8, buffer.pos as u8])
+                .unwrap();
+            stream.write_all(&buffer.buf[0..buffer.pos]).unwrap();
+
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let len = (usize::from(len[0]) << 8) | usize::from(len[1]);
+            let mut response = BytePacketBuffer::new();
+            stream.read_exact(&mut response.buf[0..len]).unwrap();
+            let response = DnsPacket::from_buffer(&mut response).unwrap();
+            assert_eq!(id, response.header.id);
+            assert!(!response.header.truncated_message);
+            assert_eq!(ResultCode::NOERROR, response.header.rescode);
+        }
+    }
+
+    #[test]
+    fn test_serve_stops() {
+        let listeners = Listeners::bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let tcp = listeners.tcp[0].local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        serve(
+            listeners,
+            Arc::new(AnswerCache::new()),
+            Arc::new(RateLimiter::new(RrlConfig::default())),
+            Arc::new(ServerCookies::new(true)),
+            Arc::new(RwLock::new(DnsConfig::default())),
+            Arc::new(Metrics::new()),
+            stop.clone(),
+        );
+        assert!(TcpStream::connect(tcp).is_ok());
+        stop.store(true, Ordering::SeqCst);
+        // the accept thread notices and drops the listener
+        thread::sleep(STOP_POLL_INTERVAL * 3);
+        assert!(TcpStream::connect(tcp).is_err());
+    }
 }
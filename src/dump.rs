@@ -0,0 +1,143 @@
+use std::cmp::Ordering;
+use std::fmt::Write;
+
+use super::db;
+use super::utils;
+
+// A text dump of the node table in the same layout as bitcoin-seeder's
+// `dnsseed.dump`, so scripts written against it work with our output too.
+
+const HEADER: &str = "# address                                        good  lastSuccess    %(2h)   %(8h)   %(1d)   %(7d)  %(30d)  blocks      svcs  version\n";
+
+// good nodes are the ones we'd hand out in DNS answers
+fn is_good(db: &db::NodeDb, node: &db::Node, port: u16) -> bool {
+    node.state == db::NodeState::Online
+        && node.addr.port() == port
+        && !db.bans().is_banned(&node.addr)
+}
+
+// the nodes out of `nodes` that belong in the dump, each with whether it's
+// good; this needs the ban list, the rest can wait until the lock is dropped
+pub fn mark_good(db: &db::NodeDb, nodes: Vec<db::Node>, port: u16) -> Vec<(bool, db::Node)> {
+    nodes
+        .into_iter()
+        .filter(|node| node.stats.last_update.is_some())
+        .map(|node| (is_good(db, &node, port), node))
+        .collect()
+}
+
+pub fn format_dump(mut nodes: Vec<(bool, db::Node)>) -> String {
+    // good nodes first, then the most reliable over 30 days
+    nodes.sort_by(|(a_good, a), (b_good, b)| {
+        b_good
+            .cmp(a_good)
+            .then(
+                b.stats.uptime[4]
+                    .reliability
+                    .partial_cmp(&a.stats.uptime[4].reliability)
+                    .unwrap_or(Ordering::Equal),
+            )
+            .then(a.addr.cmp(&b.addr))
+    });
+
+    let mut out = String::from(HEADER);
+    for (good, node) in nodes {
+        let uptime: Vec<f64> = node
+            .stats
+            .uptime
+            .iter()
+            .map(|w| 100.0 * w.reliability)
+            .collect();
+        let (blocks, services, version, user_agent) = match node.version {
+            Some(ref v) => (v.start_height, v.services, v.version, v.user_agent.as_str()),
+            None => (0, 0, 0, ""),
+        };
+        writeln!(
+            out,
+            "{:<47}  {:>4}  {:>11}  {:>6.2}% {:>6.2}% {:>6.2}% {:>6.2}% {:>7.2}%  {:>6}  {:08x}  {:>5} \"{}\"",
+            node.addr.to_string(),
+            good as u8,
+            node.stats.last_success.map_or(0, utils::unix_time),
+            uptime[0],
+            uptime[1],
+            uptime[2],
+            uptime[3],
+            uptime[4],
+            blocks,
+            services,
+            version,
+            user_agent,
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_format_dump() {
+        let mut db = db::NodeDb::new();
+        let mut stats = db::NodeStats::default();
+        let start = UNIX_EPOCH + Duration::new(1_500_000_000, 0);
+        stats.record_visit(true, start);
+        stats.record_visit(true, start + Duration::new(3600, 0));
+        db.insert(db::Node {
+            addr: "1.2.3.4:8333".parse().unwrap(),
+            state: db::NodeState::Online,
+            last_visit: start,
+            version: Some(db::VersionInfo {
+                version: 70015,
+                services: 1033,
+                user_agent: String::from("/Satoshi:0.18.0/"),
+                start_height: 600000,
+            }),
+            stats: stats.clone(),
+        });
+        db.insert(db::Node {
+            addr: "5.6.7.8:8334".parse().unwrap(),
+            state: db::NodeState::Online,
+            last_visit: start,
+            version: None,
+            stats,
+        });
+        // never visited, left out
+        db.init("9.9.9.9:8333".parse().unwrap());
+
+        let dump = format_dump(mark_good(&db, db.nodes(), 8333));
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(3, lines.len());
+        assert_eq!(HEADER.trim_end(), lines[0]);
+        // bitcoin-seeder's "%-47s  %4d  %11" PRId64 "  %6.2f%% %6.2f%% %6.2f%%
+        // %6.2f%% %7.2f%%  %6i  %08" PRIx64 "  %5i \"%s\"", field by field
+        let expected = concat!(
+            "1.2.3.4:8333                                   ",
+            "  ",
+            "   1",
+            "  ",
+            " 1500003600",
+            "  ",
+            " 39.35%",
+            " ",
+            " 11.75%",
+            " ",
+            "  4.08%",
+            " ",
+            "  0.59%",
+            " ",
+            "   0.14%",
+            "  ",
+            "600000",
+            "  ",
+            "00000409",
+            "  ",
+            "70015",
+            " \"/Satoshi:0.18.0/\"",
+        );
+        assert_eq!(expected, lines[1]);
+        assert!(lines[2].starts_with("5.6.7.8:8334                                        0 "));
+    }
+}
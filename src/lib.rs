@@ -0,0 +1,31 @@
+//! A DNS seed for the Bitcoin network.
+//!
+//! The crawler ([`crawler`]) connects to nodes, records what they tell us in a
+//! [`db::NodeDb`], and follows the addresses they gossip. The DNS server
+//! ([`dns`]) answers queries for the seed name with nodes that are online.
+//! The `bitcoin-seed` binary wires these together with [`crawler::crawl`].
+
+pub mod admin;
+pub mod answers;
+pub mod ban;
+pub mod config;
+pub mod cookie;
+pub mod crawler;
+pub mod db;
+pub mod dns;
+pub mod metrics;
+pub mod probe;
+pub mod rrl;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+mod api;
+mod dump;
+mod peers;
+mod privs;
+mod resolver;
+mod seeds;
+mod systemd;
+mod utils;
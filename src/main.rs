@@ -1,8 +1,66 @@
-mod crawler;
-mod db;
-mod dns;
-mod utils;
+use bitcoin_seed::{admin, config, crawler, probe};
+use std::env;
+use std::path::Path;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "usage: {} [--config <path>] [admin <command>... | probe <ip:port>]",
+        program
+    );
+    process::exit(1);
+}
 
 fn main() {
-    crawler::crawl();
+    let args: Vec<String> = env::args().collect();
+    let mut rest = &args[1..];
+    let config = match rest.first().map(|s| s.as_str()) {
+        Some("--config") => {
+            let path = match rest.get(1) {
+                Some(path) => path,
+                None => usage(&args[0]),
+            };
+            rest = &rest[2..];
+            match config::Config::load(Path::new(path)) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Couldn't load config {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => config::Config::default(),
+    };
+    match rest.first().map(|s| s.as_str()) {
+        None => crawler::crawl(config),
+        Some("admin") if rest.len() > 1 => {
+            let path = config.admin_socket_file();
+            let response = match admin::request(&path, &rest[1..].join(" ")) {
+                Ok(response) => response,
+                Err(e) => {
+                    eprintln!("Couldn't reach the seeder at {}: {}", path.display(), e);
+                    process::exit(1);
+                }
+            };
+            println!("{}", serde_json::to_string_pretty(&response).unwrap());
+            if response.get("error").is_some() {
+                process::exit(1);
+            }
+        }
+        Some("probe") if rest.len() == 2 => {
+            let addr = match rest[1].parse() {
+                Ok(addr) => addr,
+                Err(_) => {
+                    eprintln!("Not an address: {}", rest[1]);
+                    process::exit(1);
+                }
+            };
+            let probe = probe::probe(addr);
+            println!("{}", serde_json::to_string_pretty(&probe).unwrap());
+            if probe.error.is_some() {
+                process::exit(1);
+            }
+        }
+        _ => usage(&args[0]),
+    }
 }
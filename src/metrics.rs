@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::db;
+
+// Counters shared by the crawler and DNS threads, rendered in the Prometheus
+// text format by the HTTP thread. Node counts aren't tracked here, they're
+// read from the database at scrape time.
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// a counter per combination of label values
+pub struct CounterVec {
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    fn new(labels: &'static [&'static str]) -> CounterVec {
+        CounterVec {
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, values: &[&str]) {
+        let key = values.iter().map(|v| v.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+}
+
+// observations are integers in some base unit, `per_unit` of which make up
+// the exported unit (e.g. 1_000_000 microseconds per second)
+pub struct Histogram {
+    bounds: &'static [u64],
+    per_unit: f64,
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64], per_unit: f64) -> Histogram {
+        Histogram {
+            bounds,
+            per_unit,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            if value <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_micros() as u64);
+    }
+}
+
+// microseconds
+const LATENCY_BUCKETS: [u64; 8] = [
+    10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000,
+];
+// records per response
+const ANSWER_BUCKETS: [u64; 7] = [0, 1, 5, 10, 15, 20, 25];
+
+pub struct Metrics {
+    pub visits: CounterVec,
+    pub connect_latency: Histogram,
+    pub addresses_learned: Counter,
+    pub dns_queries: CounterVec,
+    pub dns_malformed: Counter,
+    pub dns_forwards_dropped: Counter,
+    pub dns_rrl: CounterVec,
+    pub dns_answer_records: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            visits: CounterVec::new(&["outcome"]),
+            connect_latency: Histogram::new(&LATENCY_BUCKETS, 1e6),
+            addresses_learned: Counter::new(),
+            dns_queries: CounterVec::new(&["qtype", "rcode"]),
+            dns_malformed: Counter::new(),
+            dns_forwards_dropped: Counter::new(),
+            dns_rrl: CounterVec::new(&["action"]),
+            dns_answer_records: Histogram::new(&ANSWER_BUCKETS, 1.0),
+        }
+    }
+
+    pub fn render(&self, db: &db::NodeDb) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "seeder_nodes", "gauge", "Known nodes by state");
+        let report = db.report();
+        for (state, name) in &[
+            (db::NodeState::Online, "online"),
+            (db::NodeState::Offline, "offline"),
+            (db::NodeState::Uncontacted, "uncontacted"),
+        ] {
+            writeln!(out, "seeder_nodes{{state=\"{}\"}} {}", name, report[state]).unwrap();
+        }
+
+        header(&mut out, "seeder_visits_total", "counter", "Node visits by outcome");
+        render_counter_vec(&mut out, "seeder_visits_total", &self.visits);
+
+        header(
+            &mut out,
+            "seeder_connect_latency_seconds",
+            "histogram",
+            "Time to establish TCP connections to nodes",
+        );
+        render_histogram(&mut out, "seeder_connect_latency_seconds", &self.connect_latency);
+
+        header(
+            &mut out,
+            "seeder_addresses_learned_total",
+            "counter",
+            "Addresses received in addr messages",
+        );
+        writeln!(out, "seeder_addresses_learned_total {}", self.addresses_learned.get()).unwrap();
+
+        header(
+            &mut out,
+            "seeder_dns_queries_total",
+            "counter",
+            "DNS queries answered by query type and response code",
+        );
+        render_counter_vec(&mut out, "seeder_dns_queries_total", &self.dns_queries);
+
+        header(
+            &mut out,
+            "seeder_dns_malformed_packets_total",
+            "counter",
+            "DNS packets that couldn't be parsed",
+        );
+        writeln!(out, "seeder_dns_malformed_packets_total {}", self.dns_malformed.get()).unwrap();
+
+        header(
+            &mut out,
+            "seeder_dns_forwards_dropped_total",
+            "counter",
+            "Forwarded queries answered SERVFAIL because the forward queue was full",
+        );
+        writeln!(
+            out,
+            "seeder_dns_forwards_dropped_total {}",
+            self.dns_forwards_dropped.get()
+        )
+        .unwrap();
+
+        header(
+            &mut out,
+            "seeder_dns_rate_limited_total",
+            "counter",
+            "DNS responses over the rate limit, by whether they were dropped or slipped",
+        );
+        render_counter_vec(&mut out, "seeder_dns_rate_limited_total", &self.dns_rrl);
+
+        header(
+            &mut out,
+            "seeder_dns_answer_records",
+            "histogram",
+            "Answer records per DNS response",
+        );
+        render_histogram(&mut out, "seeder_dns_answer_records", &self.dns_answer_records);
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn render_counter_vec(out: &mut String, name: &str, counter: &CounterVec) {
+    for (values, count) in counter.values.lock().unwrap().iter() {
+        let labels: Vec<String> = counter
+            .labels
+            .iter()
+            .zip(values.iter())
+            .map(|(label, value)| format!("{}=\"{}\"", label, value))
+            .collect();
+        writeln!(out, "{}{{{}}} {}", name, labels.join(","), count).unwrap();
+    }
+}
+
+fn render_histogram(out: &mut String, name: &str, histogram: &Histogram) {
+    for (bound, bucket) in histogram.bounds.iter().zip(histogram.buckets.iter()) {
+        writeln!(
+            out,
+            "{}_bucket{{le=\"{}\"}} {}",
+            name,
+            *bound as f64 / histogram.per_unit,
+            bucket.load(Ordering::Relaxed)
+        )
+        .unwrap();
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+    writeln!(
+        out,
+        "{}_sum {}",
+        name,
+        histogram.sum.load(Ordering::Relaxed) as f64 / histogram.per_unit
+    )
+    .unwrap();
+    writeln!(out, "{}_count {}", name, count).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.visits.inc(&["online"]);
+        metrics.visits.inc(&["online"]);
+        metrics.dns_queries.inc(&["A", "NOERROR"]);
+        metrics.connect_latency.observe_duration(Duration::from_millis(30));
+        metrics.dns_answer_records.observe(25);
+
+        let mut db = db::NodeDb::new();
+        db.init("1.1.1.1:8333".parse().unwrap());
+        let out = metrics.render(&db);
+
+        assert!(out.contains("seeder_nodes{state=\"uncontacted\"} 1\n"));
+        assert!(out.contains("seeder_visits_total{outcome=\"online\"} 2\n"));
+        assert!(out.contains("seeder_dns_queries_total{qtype=\"A\",rcode=\"NOERROR\"} 1\n"));
+        assert!(out.contains("seeder_connect_latency_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(out.contains("seeder_connect_latency_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(out.contains("seeder_connect_latency_seconds_count 1\n"));
+        assert!(out.contains("seeder_dns_answer_records_bucket{le=\"20\"} 0\n"));
+        assert!(out.contains("seeder_dns_answer_records_sum 25\n"));
+    }
+}
@@ -0,0 +1,333 @@
+use bitcoin::network::constants::Network;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+use super::utils;
+
+// Readers for the address files a Bitcoin Core node keeps in its data
+// directory, so we can bootstrap from our own node instead of DNS:
+//
+// - peers.dat, the serialized address manager. Only the addresses are read,
+//   the bucket layout is skipped. Formats 0 through 4 are understood, 3 and
+//   up store addresses in the BIP155 (addrv2) encoding.
+// - anchors.dat, the block-relay-only peers a node reconnects to on startup.
+//
+// Both start with the network magic and end in a checksum, which we don't
+// verify: a corrupt file just gives us some useless addresses to crawl.
+
+// set in a stored CAddress's version field when it uses the addrv2 encoding
+const ADDRV2_FORMAT: u32 = 0x2000_0000;
+// peers.dat formats from 3 on store "32 + lowest compatible format" here
+const INCOMPATIBILITY_BASE: u8 = 32;
+const MAX_FORMAT: u8 = 4;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], io::Error> {
+        if self.data.len() - self.pos < n {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "address file is truncated",
+            ));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, io::Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_be(&mut self) -> Result<u16, io::Error> {
+        let b = self.bytes(2)?;
+        Ok((b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    fn u32_le(&mut self) -> Result<u32, io::Error> {
+        let b = self.bytes(4)?;
+        Ok(b.iter().rev().fold(0, |acc, b| acc << 8 | *b as u32))
+    }
+
+    fn u64_le(&mut self) -> Result<u64, io::Error> {
+        let b = self.bytes(8)?;
+        Ok(b.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64))
+    }
+
+    fn compact_size(&mut self) -> Result<u64, io::Error> {
+        match self.u8()? {
+            0xfd => Ok(self.bytes(2)?.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64)),
+            0xfe => Ok(self.u32_le()? as u64),
+            0xff => self.u64_le(),
+            n => Ok(n as u64),
+        }
+    }
+
+    // a CNetAddr, `None` for networks we can't crawl (Tor, I2P, CJDNS, ...)
+    fn net_addr(&mut self, addrv2: bool) -> Result<Option<IpAddr>, io::Error> {
+        if !addrv2 {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(self.bytes(16)?);
+            return Ok(ipv6_to_ip(Ipv6Addr::from(octets)));
+        }
+        let network = self.u8()?;
+        let len = self.compact_size()?;
+        if len > 512 {
+            return Err(invalid("address too long"));
+        }
+        let bytes = self.bytes(len as usize)?;
+        match (network, bytes.len()) {
+            (1, 4) => Ok(Some(IpAddr::V4(Ipv4Addr::new(
+                bytes[0], bytes[1], bytes[2], bytes[3],
+            )))),
+            (2, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(bytes);
+                Ok(ipv6_to_ip(Ipv6Addr::from(octets)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // a CAddress as stored on disk: version, time, services, address, port
+    fn address(&mut self, addrv2_stream: bool) -> Result<(Option<SocketAddr>, bool), io::Error> {
+        let version = self.u32_le()?;
+        let addrv2 = addrv2_stream || version & ADDRV2_FORMAT != 0;
+        let _time = self.u32_le()?;
+        let _services = if addrv2 {
+            self.compact_size()?
+        } else {
+            self.u64_le()?
+        };
+        let ip = self.net_addr(addrv2)?;
+        let port = self.u16_be()?;
+        Ok((ip.map(|ip| SocketAddr::new(ip, port)), addrv2))
+    }
+}
+
+// legacy 16 byte addresses carry IPv4 as ::ffff:a.b.c.d and Tor as OnionCat
+fn ipv6_to_ip(ip: Ipv6Addr) -> Option<IpAddr> {
+    let segments = ip.segments();
+    if segments[0] == 0xfd87 && segments[1] == 0xd87e && segments[2] == 0xeb43 {
+        return None;
+    }
+    match ip.to_ipv4() {
+        Some(v4) if segments[5] == 0xffff => Some(IpAddr::V4(v4)),
+        _ => Some(IpAddr::V6(ip)),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn check_magic(reader: &mut Reader, network: Network) -> Result<(), io::Error> {
+    if reader.u32_le()? != network.magic() {
+        return Err(invalid("address file is for a different network"));
+    }
+    Ok(())
+}
+
+pub fn parse_peers_dat(data: &[u8], network: Network) -> Result<Vec<SocketAddr>, io::Error> {
+    let mut reader = Reader::new(data);
+    check_magic(&mut reader, network)?;
+    let format = reader.u8()?;
+    let compat = reader.u8()?;
+    // before format 3 this byte was the key size, which was always 32
+    if compat < INCOMPATIBILITY_BASE || compat - INCOMPATIBILITY_BASE > MAX_FORMAT {
+        return Err(invalid("unsupported peers.dat format"));
+    }
+    let _key = reader.bytes(32)?;
+    let new = reader.u32_le()? as i32;
+    let tried = reader.u32_le()? as i32;
+    if new < 0 || tried < 0 {
+        return Err(invalid("negative address count"));
+    }
+    let _buckets = reader.u32_le()?;
+
+    let addrv2 = format >= 3;
+    let mut addrs = vec![];
+    for _ in 0..(new as i64 + tried as i64) {
+        let (addr, entry_v2) = reader.address(addrv2)?;
+        let _source = reader.net_addr(entry_v2)?;
+        let _last_success = reader.u64_le()?;
+        let _attempts = reader.u32_le()?;
+        if let Some(addr) = addr {
+            addrs.push(addr);
+        }
+    }
+    Ok(addrs)
+}
+
+pub fn parse_anchors_dat(data: &[u8], network: Network) -> Result<Vec<SocketAddr>, io::Error> {
+    let mut reader = Reader::new(data);
+    check_magic(&mut reader, network)?;
+    let count = reader.compact_size()?;
+    let mut addrs = vec![];
+    for _ in 0..count {
+        if let (Some(addr), _) = reader.address(true)? {
+            addrs.push(addr);
+        }
+    }
+    Ok(addrs)
+}
+
+// one address per line, `ip`, `ip:port` or `[ipv6]:port`, `#` starts a comment
+pub fn parse_address_list(contents: &str, network: Network) -> Result<Vec<SocketAddr>, io::Error> {
+    let mut addrs = vec![];
+    for line in contents.lines() {
+        let line = line.splitn(2, '#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let addr = match line.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => match line.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, utils::default_port(network)),
+                Err(_) => return Err(invalid(&format!("bad address: {}", line))),
+            },
+        };
+        addrs.push(addr);
+    }
+    Ok(addrs)
+}
+
+pub fn read_peers_dat(path: &Path, network: Network) -> Result<Vec<SocketAddr>, io::Error> {
+    parse_peers_dat(&fs::read(path)?, network)
+}
+
+pub fn read_anchors_dat(path: &Path, network: Network) -> Result<Vec<SocketAddr>, io::Error> {
+    parse_anchors_dat(&fs::read(path)?, network)
+}
+
+pub fn read_address_list(path: &Path, network: Network) -> Result<Vec<SocketAddr>, io::Error> {
+    parse_address_list(&fs::read_to_string(path)?, network)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+
+    fn header(format: u8, compat: u8, new: u32, tried: u32) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(format);
+        data.push(compat);
+        data.extend_from_slice(&[7u8; 32]);
+        data.extend_from_slice(&new.to_le_bytes());
+        data.extend_from_slice(&tried.to_le_bytes());
+        data.extend_from_slice(&(1024u32 ^ (1 << 30)).to_le_bytes());
+        data
+    }
+
+    fn legacy_entry(ip: [u8; 16], port: u16) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&190000u32.to_le_bytes()); // version
+        data.extend_from_slice(&1571000000u32.to_le_bytes()); // time
+        data.extend_from_slice(&1033u64.to_le_bytes()); // services
+        data.extend_from_slice(&ip);
+        data.extend_from_slice(&port.to_be_bytes());
+        data.extend_from_slice(&[0u8; 16]); // source
+        data.extend_from_slice(&0u64.to_le_bytes()); // last success
+        data.extend_from_slice(&0u32.to_le_bytes()); // attempts
+        data
+    }
+
+    fn v2_address(network: u8, addr: &[u8], port: u16) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&(220000u32 | ADDRV2_FORMAT).to_le_bytes());
+        data.extend_from_slice(&1571000000u32.to_le_bytes());
+        data.push(9); // services, compact size
+        data.push(network);
+        data.push(addr.len() as u8);
+        data.extend_from_slice(addr);
+        data.extend_from_slice(&port.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_legacy_peers_dat() {
+        let mut data = header(1, 32, 2, 1);
+        let mut v4 = [0u8; 16];
+        v4[10] = 0xff;
+        v4[11] = 0xff;
+        v4[12..].copy_from_slice(&[1, 2, 3, 4]);
+        data.extend(legacy_entry(v4, 8333));
+        let mut onion = [0u8; 16];
+        onion[..6].copy_from_slice(&[0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43]);
+        data.extend(legacy_entry(onion, 8333));
+        let v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        data.extend(legacy_entry(v6.octets(), 8334));
+        data.extend_from_slice(&[0u8; 32]); // checksum and buckets, ignored
+
+        let addrs = parse_peers_dat(&data, Network::Bitcoin).unwrap();
+        assert_eq!(
+            vec![
+                "1.2.3.4:8333".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:8334".parse().unwrap(),
+            ],
+            addrs
+        );
+
+        assert!(parse_peers_dat(&data, Network::Testnet).is_err());
+        assert!(parse_peers_dat(&data[..100], Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_parse_addrv2_peers_dat() {
+        let mut data = header(3, 32 + 3, 2, 0);
+        data.extend(v2_address(1, &[5, 6, 7, 8], 8333));
+        data.extend_from_slice(&[1, 4, 1, 1, 1, 1]); // source
+        data.extend_from_slice(&[0u8; 12]);
+        data.extend(v2_address(4, &[0xab; 32], 8333)); // torv3
+        data.extend_from_slice(&[1, 4, 1, 1, 1, 1]);
+        data.extend_from_slice(&[0u8; 12]);
+
+        let addrs = parse_peers_dat(&data, Network::Bitcoin).unwrap();
+        assert_eq!(vec!["5.6.7.8:8333".parse::<SocketAddr>().unwrap()], addrs);
+
+        // written by a newer version we can't read
+        let data = header(9, 32 + 9, 0, 0);
+        assert!(parse_peers_dat(&data, Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_parse_anchors_dat() {
+        let mut data = MAGIC.to_vec();
+        data.push(2);
+        data.extend(v2_address(1, &[5, 6, 7, 8], 8333));
+        let v6: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        data.extend(v2_address(2, &v6.octets(), 8333));
+        data.extend_from_slice(&[0u8; 32]);
+
+        let addrs = parse_anchors_dat(&data, Network::Bitcoin).unwrap();
+        assert_eq!(2, addrs.len());
+        assert_eq!("[2001:db8::2]:8333".parse::<SocketAddr>().unwrap(), addrs[1]);
+    }
+
+    #[test]
+    fn test_parse_address_list() {
+        let contents = "# my nodes\n1.2.3.4\n5.6.7.8:8334 # backup\n\n[2001:db8::1]:8333\n";
+        let addrs = parse_address_list(contents, Network::Bitcoin).unwrap();
+        assert_eq!(
+            vec![
+                "1.2.3.4:8333".parse::<SocketAddr>().unwrap(),
+                "5.6.7.8:8334".parse().unwrap(),
+                "[2001:db8::1]:8333".parse().unwrap(),
+            ],
+            addrs
+        );
+        assert!(parse_address_list("not-an-ip\n", Network::Bitcoin).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+// Giving up root once the DNS sockets are bound, so the crawler and the P2P
+// message parsing never run with more rights than they need.
+
+fn c_string(s: &[u8]) -> Result<CString, io::Error> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn check(ret: libc::c_int) -> Result<(), io::Error> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// uid and primary gid of `user`
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t), io::Error> {
+    let name = c_string(user.as_bytes())?;
+    // only called before any other thread looks up users, so the static
+    // buffer behind getpwnam is fine
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such user: {}", user),
+        ));
+    }
+    unsafe { Ok(((*passwd).pw_uid, (*passwd).pw_gid)) }
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t, io::Error> {
+    let name = c_string(group.as_bytes())?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such group: {}", group),
+        ));
+    }
+    unsafe { Ok((*entry).gr_gid) }
+}
+
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+// switch to `user` and `group` (the user's primary group by default), after
+// confining ourselves to `chroot` if given
+pub fn drop_privileges(
+    user: &str,
+    group: Option<&str>,
+    chroot: Option<&Path>,
+) -> Result<(), io::Error> {
+    let (uid, mut gid) = lookup_user(user)?;
+    if let Some(group) = group {
+        gid = lookup_group(group)?;
+    }
+    if let Some(dir) = chroot {
+        let dir = c_string(dir.as_os_str().as_bytes())?;
+        check(unsafe { libc::chroot(dir.as_ptr()) })?;
+        check(unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) })?;
+    }
+    // supplementary groups first, we can't change them once we're not root
+    check(unsafe { libc::setgroups(1, &gid) })?;
+    check(unsafe { libc::setgid(gid) })?;
+    check(unsafe { libc::setuid(uid) })?;
+    // make sure there's no way back
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "still able to regain root",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_user() {
+        // fails in the lookup, before anything about the process changes
+        let err = drop_privileges("no-such-user-for-seeder-tests", None, None).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        let err =
+            drop_privileges("root", Some("no-such-group-for-seeder-tests"), None).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+}
@@ -0,0 +1,133 @@
+//! A single [`crawler::visit`] run on its own, for debugging one node: no
+//! DNS server, no node database, just what the node said.
+
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::crawler;
+use super::crawler::{CrawlerError, WorkerOutput};
+use super::db;
+use super::db::VisitOutcome;
+
+/// Everything one visit learned, ready to print as JSON.
+#[derive(Debug, Serialize)]
+pub struct Probe {
+    pub addr: SocketAddr,
+    // "online", "offline" or "error", as in the visit log
+    pub outcome: &'static str,
+    pub error: Option<String>,
+    pub connect_latency_ms: Option<u64>,
+    // the whole visit, handshake and `getaddr` included
+    pub duration_ms: u64,
+    pub version: Option<ProbeVersion>,
+    // addresses in the node's answer to `getaddr`
+    pub addrs: usize,
+}
+
+/// The fields of the node's `version` message.
+#[derive(Debug, Serialize)]
+pub struct ProbeVersion {
+    pub version: u32,
+    pub services: u64,
+    pub timestamp: i64,
+    // our address as the node sees it
+    pub receiver: Option<SocketAddr>,
+    pub nonce: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    pub relay: bool,
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+impl Probe {
+    fn new(
+        addr: SocketAddr,
+        result: Result<WorkerOutput, CrawlerError>,
+        duration: Duration,
+    ) -> Probe {
+        let mut probe = Probe {
+            addr,
+            outcome: VisitOutcome::Error.as_str(),
+            error: None,
+            connect_latency_ms: None,
+            duration_ms: millis(duration),
+            version: None,
+            addrs: 0,
+        };
+        let output = match result {
+            Ok(output) => output,
+            Err(e) => {
+                probe.error = Some(e.to_string());
+                return probe;
+            }
+        };
+        probe.connect_latency_ms = output.connect_latency.map(millis);
+        probe.addrs = output.addr_msg.map_or(0, |addrs| addrs.len());
+        probe.version = output.version_msg.map(|version| ProbeVersion {
+            version: version.version,
+            services: version.services,
+            timestamp: version.timestamp,
+            receiver: version.receiver.socket_addr().ok(),
+            nonce: version.nonce,
+            user_agent: version.user_agent,
+            start_height: version.start_height,
+            relay: version.relay,
+        });
+        probe.outcome = match probe.version {
+            Some(_) => VisitOutcome::Online.as_str(),
+            None => VisitOutcome::Offline.as_str(),
+        };
+        probe
+    }
+}
+
+/// Visit `addr` the way a crawler worker would and report what happened.
+pub fn probe(addr: SocketAddr) -> Probe {
+    let start = Instant::now();
+    let result = crawler::visit(db::Node::new(addr));
+    Probe::new(addr, result, start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_probe_refused() {
+        // bound and closed again, so nothing is listening there
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let probe = probe(addr);
+        assert_eq!("error", probe.outcome);
+        assert!(probe.error.is_some());
+        assert!(probe.version.is_none());
+        assert_eq!(None, probe.connect_latency_ms);
+    }
+
+    #[test]
+    fn test_probe_offline() {
+        // connected, but the node hung up before sending `version`
+        let output = WorkerOutput {
+            node: db::Node::new("1.2.3.4:8333".parse().unwrap()),
+            connect_latency: Some(Duration::from_millis(25)),
+            version_msg: None,
+            addr_msg: None,
+        };
+        let probe = Probe::new(
+            "1.2.3.4:8333".parse().unwrap(),
+            Ok(output),
+            Duration::from_millis(1500),
+        );
+        assert_eq!("offline", probe.outcome);
+        assert_eq!(Some(25), probe.connect_latency_ms);
+        assert_eq!(1500, probe.duration_ms);
+        assert_eq!(0, probe.addrs);
+    }
+}
@@ -0,0 +1,103 @@
+use log::trace;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use super::dns;
+use super::dns::{DnsPacket, DnsRecord, QueryType, ResultCode};
+
+// A stub resolver that asks upstream recursive servers directly, so looking
+// up other DNS seeds doesn't depend on the system resolver (which we may have
+// disabled to get port 53).
+
+pub struct Resolver {
+    servers: Vec<SocketAddr>,
+    timeout: Duration,
+    attempts: usize,
+}
+
+impl Resolver {
+    pub fn new(servers: Vec<SocketAddr>, timeout: Duration, attempts: usize) -> Resolver {
+        Resolver {
+            servers,
+            timeout,
+            attempts,
+        }
+    }
+
+    // ask each server in turn until one gives a valid answer, `attempts` rounds
+    pub fn query(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket, io::Error> {
+        let mut last_err = io::Error::new(io::ErrorKind::Other, "no upstream servers");
+        for _ in 0..self.attempts {
+            for server in self.servers.iter() {
+                match dns::lookup(qname, qtype, *server, self.timeout, 1) {
+                    Ok(packet) => match packet.header.rescode {
+                        ResultCode::NOERROR | ResultCode::NXDOMAIN => return Ok(packet),
+                        rescode => {
+                            trace!("{} answered {:?} for {}", server, rescode, qname);
+                            last_err = io::Error::new(
+                                io::ErrorKind::Other,
+                                format!("server answered {:?}", rescode),
+                            );
+                        }
+                    },
+                    Err(e) => {
+                        trace!("{:?} query for {} to {} failed: {}", qtype, qname, server, e);
+                        last_err = match e {
+                            dns::LookupError::Io(e) => e,
+                            e => io::Error::new(io::ErrorKind::TimedOut, e.to_string()),
+                        };
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    // A and AAAA records for `host`, with `port` attached
+    pub fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, io::Error> {
+        let mut addrs = vec![];
+        let mut last_err = None;
+        for qtype in &[QueryType::A, QueryType::AAAA] {
+            match self.query(host, *qtype) {
+                Ok(packet) => {
+                    for record in packet.answers {
+                        match record {
+                            DnsRecord::A { addr, .. } => {
+                                addrs.push(SocketAddr::new(IpAddr::V4(addr), port))
+                            }
+                            DnsRecord::AAAA { addr, .. } => {
+                                addrs.push(SocketAddr::new(IpAddr::V6(addr), port))
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        match last_err {
+            Some(e) if addrs.is_empty() => Err(e),
+            _ => Ok(addrs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn test_query_times_out() {
+        // bound but never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolver = Resolver::new(
+            vec![silent.local_addr().unwrap()],
+            Duration::from_millis(100),
+            2,
+        );
+        let err = resolver.query("seed.example.com", QueryType::A).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    }
+}
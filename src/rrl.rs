@@ -0,0 +1,270 @@
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Response rate limiting, so the seeder is a poor amplifier for reflection
+// attacks with spoofed sources. Clients are grouped by network prefix, each
+// group gets `responses_per_second` of credit, and responses over that are
+// dropped except for every `slip`th, which is sent truncated and empty so a
+// real resolver behind the prefix retries over TCP.
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RrlConfig {
+    // 0 turns rate limiting off
+    pub responses_per_second: u32,
+    // send every nth limited response truncated, 0 to drop them all
+    pub slip: u32,
+    // seconds of excess a prefix can build up debt for, so a flood keeps
+    // being limited for a while after it slows down
+    pub window: u64,
+    // prefixes tracked at once; when the table fills up, the tenth of them
+    // seen least recently are forgotten
+    pub max_entries: usize,
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+}
+
+impl Default for RrlConfig {
+    fn default() -> RrlConfig {
+        RrlConfig {
+            responses_per_second: 20,
+            slip: 2,
+            window: 15,
+            max_entries: 100_000,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Send,
+    Slip,
+    Drop,
+}
+
+struct Bucket {
+    // responses we can still send, negative when in debt
+    balance: f64,
+    last: Instant,
+    // limited responses so far, to pick which ones slip
+    limited: u64,
+}
+
+// the buckets, and an index of them by when they were last used so the
+// quietest prefixes can be found without a scan when the table is full
+#[derive(Default)]
+struct Table {
+    buckets: HashMap<IpAddr, Bucket>,
+    by_last: BTreeSet<(Instant, IpAddr)>,
+}
+
+impl Table {
+    // forget the least recently seen prefixes until at most `keep` are left
+    fn evict(&mut self, keep: usize) {
+        while self.buckets.len() > keep {
+            let oldest = match self.by_last.iter().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            self.by_last.remove(&oldest);
+            self.buckets.remove(&oldest.1);
+        }
+    }
+}
+
+pub struct RateLimiter {
+    config: RwLock<RrlConfig>,
+    table: Mutex<Table>,
+}
+
+fn prefix(ip: IpAddr, config: &RrlConfig) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let len = u32::from(config.ipv4_prefix_len.min(32));
+            let mask = if len == 0 { 0 } else { !0u32 << (32 - len) };
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let len = u32::from(config.ipv6_prefix_len.min(128));
+            let mask = if len == 0 { 0 } else { !0u128 << (128 - len) };
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RrlConfig) -> RateLimiter {
+        RateLimiter {
+            config: RwLock::new(config),
+            table: Mutex::new(Table::default()),
+        }
+    }
+
+    pub fn config(&self) -> RrlConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    // switch to `config`, starting every prefix over
+    pub fn reconfigure(&self, config: RrlConfig) {
+        let mut current = self.config.write().unwrap();
+        *current = config;
+        *self.table.lock().unwrap() = Table::default();
+    }
+
+    // what to do with a response to `ip` that's about to go out at `now`
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Action {
+        let config = self.config.read().unwrap();
+        let rate = f64::from(config.responses_per_second);
+        if rate == 0.0 {
+            return Action::Send;
+        }
+        let window = Duration::from_secs(config.window);
+        let key = prefix(ip, &config);
+        let mut table = self.table.lock().unwrap();
+        if table.buckets.len() >= config.max_entries && !table.buckets.contains_key(&key) {
+            // in one go, so a flood of new prefixes only pays for this once
+            // every `max_entries / 10` queries
+            let batch = (config.max_entries / 10).max(1);
+            table.evict(config.max_entries.saturating_sub(batch));
+        }
+
+        let table = &mut *table;
+        let bucket = table.buckets.entry(key).or_insert(Bucket {
+            balance: rate,
+            last: now,
+            limited: 0,
+        });
+        table.by_last.remove(&(bucket.last, key));
+        table.by_last.insert((now, key));
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.last = now;
+        bucket.balance = (bucket.balance + elapsed * rate).min(rate);
+        bucket.balance = (bucket.balance - 1.0).max(-rate * window.as_secs_f64());
+        if bucket.balance >= 0.0 {
+            return Action::Send;
+        }
+        bucket.limited += 1;
+        let slip = u64::from(config.slip);
+        if slip > 0 && bucket.limited % slip == 0 {
+            Action::Slip
+        } else {
+            Action::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: u32, slip: u32) -> RateLimiter {
+        RateLimiter::new(RrlConfig {
+            responses_per_second: rate,
+            slip,
+            window: 5,
+            ..RrlConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_limit_and_slip() {
+        let rrl = limiter(3, 2);
+        let now = Instant::now();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let actions: Vec<Action> = (0..7).map(|_| rrl.check(ip, now)).collect();
+        assert_eq!(
+            vec![
+                Action::Send,
+                Action::Send,
+                Action::Send,
+                Action::Drop,
+                Action::Slip,
+                Action::Drop,
+                Action::Slip,
+            ],
+            actions
+        );
+        // same /24 shares the budget, other networks don't
+        assert_ne!(Action::Send, rrl.check("10.0.0.200".parse().unwrap(), now));
+        assert_eq!(Action::Send, rrl.check("10.0.1.1".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn test_refill_after_debt() {
+        let rrl = limiter(2, 0);
+        let start = Instant::now();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        for _ in 0..20 {
+            rrl.check(ip, start);
+        }
+        // paying off the debt takes longer than a second
+        assert_eq!(Action::Drop, rrl.check(ip, start + Duration::from_secs(1)));
+        assert_eq!(Action::Send, rrl.check(ip, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let rrl = limiter(1, 0);
+        let now = Instant::now();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(Action::Send, rrl.check(ip, now));
+        assert_eq!(Action::Drop, rrl.check(ip, now));
+        rrl.reconfigure(RrlConfig {
+            responses_per_second: 0,
+            ..RrlConfig::default()
+        });
+        assert_eq!(Action::Send, rrl.check(ip, now));
+        assert_eq!(0, rrl.config().responses_per_second);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let rrl = limiter(0, 2);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(Action::Send, rrl.check("10.0.0.1".parse().unwrap(), now));
+        }
+    }
+
+    #[test]
+    fn test_bounded_table() {
+        let rrl = RateLimiter::new(RrlConfig {
+            max_entries: 10,
+            ..RrlConfig::default()
+        });
+        let now = Instant::now();
+        for i in 0..100u32 {
+            rrl.check(IpAddr::V4(Ipv4Addr::from(i << 8)), now);
+        }
+        assert!(rrl.table.lock().unwrap().buckets.len() <= 10);
+    }
+
+    #[test]
+    fn test_full_table_keeps_recent_prefixes() {
+        let rrl = RateLimiter::new(RrlConfig {
+            responses_per_second: 1,
+            slip: 0,
+            max_entries: 100,
+            ..RrlConfig::default()
+        });
+        let start = Instant::now();
+        let flooder: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(Action::Send, rrl.check(flooder, start));
+        // spoofed queries from ever new /56s keep the table full, but the
+        // flooder's own bucket stays, in debt
+        for i in 0..10_000u32 {
+            let now = start + Duration::from_micros(u64::from(i));
+            let spoofed = IpAddr::V6(Ipv6Addr::from(u128::from(i) << 72));
+            assert_eq!(Action::Send, rrl.check(spoofed, now));
+            assert_eq!(Action::Drop, rrl.check(flooder, now));
+            let table = rrl.table.lock().unwrap();
+            assert!(table.buckets.len() <= 100);
+            assert_eq!(table.buckets.len(), table.by_last.len());
+        }
+    }
+}
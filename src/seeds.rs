@@ -0,0 +1,43 @@
+use bitcoin::network::constants::Network;
+use std::net::SocketAddr;
+
+use super::peers;
+
+// Addresses compiled into the binary, like Bitcoin Core's chainparamsseeds,
+// for when every DNS seed is unreachable. Regenerate the lists from our own
+// dnsseed.dump with contrib/seeds/generate.sh.
+
+const MAIN_SEEDS: &str = include_str!("../contrib/seeds/nodes_main.txt");
+const TEST_SEEDS: &str = include_str!("../contrib/seeds/nodes_test.txt");
+
+pub fn fixed_seeds(network: Network) -> Vec<SocketAddr> {
+    let list = match network {
+        Network::Bitcoin => MAIN_SEEDS,
+        Network::Testnet => TEST_SEEDS,
+        _ => return vec![],
+    };
+    peers::parse_address_list(list, network).expect("invalid fixed seed list")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the address lines, without comments and blank lines
+    fn entries(list: &str) -> usize {
+        list.lines()
+            .filter(|line| !line.splitn(2, '#').next().unwrap().trim().is_empty())
+            .count()
+    }
+
+    #[test]
+    fn test_fixed_seeds_parse() {
+        let main = fixed_seeds(Network::Bitcoin);
+        assert!(!main.is_empty());
+        assert_eq!(entries(MAIN_SEEDS), main.len());
+        assert!(main.iter().all(|addr| addr.port() == 8333));
+        let test = fixed_seeds(Network::Testnet);
+        assert_eq!(entries(TEST_SEEDS), test.len());
+        assert!(test.iter().all(|addr| addr.port() == 18333));
+    }
+}
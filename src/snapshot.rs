@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::db::{MemoryStore, Node, NodeState, NodeStore};
+use super::utils;
+
+/// A [`NodeStore`] that keeps nodes in memory and writes all of them to a
+/// JSON file on `flush`, so a restart picks up where the crawler left off.
+pub struct SnapshotStore {
+    memory: MemoryStore,
+    path: PathBuf,
+}
+
+impl SnapshotStore {
+    /// Load the snapshot at `path`, starting empty if it doesn't exist yet.
+    pub fn open(path: &Path) -> Result<SnapshotStore, io::Error> {
+        let mut memory = MemoryStore::new();
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                let nodes: Vec<Node> = serde_json::from_str(&contents)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                for node in nodes {
+                    memory.insert(node);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(SnapshotStore {
+            memory,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl NodeStore for SnapshotStore {
+    fn get(&self, addr: &SocketAddr) -> Option<Node> {
+        self.memory.get(addr)
+    }
+    fn nodes(&self) -> Vec<Node> {
+        self.memory.nodes()
+    }
+    fn init(&mut self, addr: SocketAddr) {
+        self.memory.init(addr)
+    }
+    fn insert(&mut self, node: Node) {
+        self.memory.insert(node)
+    }
+    fn next(&mut self, due: SystemTime, skip: &dyn Fn(&SocketAddr) -> bool) -> Option<Node> {
+        self.memory.next(due, skip)
+    }
+    fn fetch_online_nodes(
+        &self,
+        n: usize,
+        port: u16,
+        skip: &dyn Fn(&SocketAddr) -> bool,
+    ) -> Vec<Node> {
+        self.memory.fetch_online_nodes(n, port, skip)
+    }
+    fn report(&self) -> HashMap<NodeState, i32> {
+        self.memory.report()
+    }
+    fn flush(&self) -> io::Result<()> {
+        let contents = serde_json::to_string(&self.memory.nodes())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        utils::write_atomic(&self.path, &contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{NodeStats, VersionInfo};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("seed-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nodes.json");
+        let _ = fs::remove_file(&path);
+
+        let mut stats = NodeStats::default();
+        stats.record_visit(true, UNIX_EPOCH + Duration::new(1_500_000_000, 0));
+        let node = Node {
+            addr: "1.2.3.4:8333".parse().unwrap(),
+            state: NodeState::Online,
+            last_visit: UNIX_EPOCH + Duration::new(1_500_000_000, 0),
+            version: Some(VersionInfo {
+                version: 70015,
+                services: 1033,
+                user_agent: String::from("/Satoshi:0.18.0/"),
+                start_height: 600000,
+            }),
+            stats,
+        };
+
+        let mut store = SnapshotStore::open(&path).unwrap();
+        store.insert(node.clone());
+        store.init("5.6.7.8:8333".parse().unwrap());
+        store.flush().unwrap();
+
+        let store = SnapshotStore::open(&path).unwrap();
+        assert_eq!(Some(node), store.get(&"1.2.3.4:8333".parse().unwrap()));
+        assert_eq!(1, store.report()[&NodeState::Uncontacted]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
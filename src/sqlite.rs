@@ -0,0 +1,492 @@
+use log::{error, warn};
+use rusqlite::types::{ToSql, Type};
+use rusqlite::{params, Connection, Row, NO_PARAMS};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::db::{Node, NodeState, NodeStats, NodeStore, VersionInfo, Visit, VisitOutcome};
+use super::utils;
+
+// `nodes` holds the latest state of every node, the same thing the other
+// stores keep. `visits` is append-only: a row per visit, trimmed to the
+// retention period on every flush, for looking at the network over time.
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    CREATE TABLE IF NOT EXISTS nodes (
+        addr TEXT PRIMARY KEY,
+        port INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        last_visit INTEGER NOT NULL,
+        version INTEGER,
+        services INTEGER,
+        user_agent TEXT,
+        start_height INTEGER,
+        stats TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS nodes_last_visit ON nodes (last_visit);
+    CREATE INDEX IF NOT EXISTS nodes_state_port ON nodes (state, port);
+    CREATE TABLE IF NOT EXISTS visits (
+        time INTEGER NOT NULL,
+        addr TEXT NOT NULL,
+        outcome TEXT NOT NULL,
+        latency_ms INTEGER,
+        version INTEGER,
+        services INTEGER,
+        start_height INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS visits_time ON visits (time);
+    CREATE INDEX IF NOT EXISTS visits_addr ON visits (addr, time);
+";
+
+const NODE_COLUMNS: &str =
+    "addr, state, last_visit, version, services, user_agent, start_height, stats";
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn state_str(state: &NodeState) -> &'static str {
+    match state {
+        NodeState::Online => "online",
+        NodeState::Offline => "offline",
+        NodeState::Uncontacted => "uncontacted",
+    }
+}
+
+fn parse_state(s: &str) -> NodeState {
+    match s {
+        "online" => NodeState::Online,
+        "offline" => NodeState::Offline,
+        _ => NodeState::Uncontacted,
+    }
+}
+
+fn from_unix(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs as u64)
+}
+
+fn node_from_row(row: &Row) -> rusqlite::Result<Node> {
+    let addr: String = row.get(0)?;
+    // the database may have been edited by hand
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))?;
+    let state: String = row.get(1)?;
+    let version: Option<i64> = row.get(3)?;
+    let stats: String = row.get(7)?;
+    let version = match version {
+        Some(version) => Some(VersionInfo {
+            version: version as u32,
+            services: row.get::<_, i64>(4)? as u64,
+            user_agent: row.get(5)?,
+            start_height: row.get(6)?,
+        }),
+        None => None,
+    };
+    Ok(Node {
+        addr,
+        state: parse_state(&state),
+        last_visit: from_unix(row.get(2)?),
+        version,
+        stats: serde_json::from_str(&stats).unwrap_or_default(),
+    })
+}
+
+// a row that doesn't make a node is logged and left out, rather than failing
+// the whole query
+fn skip_bad_row(node: rusqlite::Result<Node>) -> rusqlite::Result<Option<Node>> {
+    match node {
+        Ok(node) => Ok(Some(node)),
+        Err(e @ rusqlite::Error::FromSqlConversionFailure(..))
+        | Err(e @ rusqlite::Error::InvalidColumnType(..)) => {
+            warn!("Skipping unreadable row in nodes: {}", e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// A [`NodeStore`] in an SQLite database, which also keeps a log of every
+/// visit for `retention`.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    retention: Duration,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path, retention: Duration) -> Result<SqliteStore, io::Error> {
+        let conn = Connection::open(path).map_err(to_io)?;
+        conn.execute_batch(SCHEMA).map_err(to_io)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+            retention,
+        })
+    }
+
+    /// Logged visits to `addr`, oldest first.
+    pub fn visits(&self, addr: &SocketAddr) -> Result<Vec<Visit>, io::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT time, outcome, latency_ms, version, services, start_height
+                 FROM visits WHERE addr = ?1 ORDER BY time, rowid",
+            )
+            .map_err(to_io)?;
+        let rows = stmt
+            .query_map(params![addr.to_string()], |row| {
+                let outcome: String = row.get(1)?;
+                let latency: Option<i64> = row.get(2)?;
+                let version: Option<i64> = row.get(3)?;
+                let version = match version {
+                    Some(version) => Some(VersionInfo {
+                        version: version as u32,
+                        services: row.get::<_, i64>(4)? as u64,
+                        // not logged, it's in `nodes` and rarely changes
+                        user_agent: String::new(),
+                        start_height: row.get(5)?,
+                    }),
+                    None => None,
+                };
+                Ok(Visit {
+                    time: from_unix(row.get(0)?),
+                    addr: *addr,
+                    outcome: match outcome.as_str() {
+                        "online" => VisitOutcome::Online,
+                        "offline" => VisitOutcome::Offline,
+                        _ => VisitOutcome::Error,
+                    },
+                    latency: latency.map(|ms| Duration::from_millis(ms as u64)),
+                    version,
+                })
+            })
+            .map_err(to_io)?;
+        rows.collect::<rusqlite::Result<Vec<Visit>>>()
+            .map_err(to_io)
+    }
+
+    /// Drop logged visits from before `before`.
+    pub fn prune_visits(&self, before: SystemTime) -> Result<usize, io::Error> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM visits WHERE time < ?1",
+                params![utils::unix_time(before) as i64],
+            )
+            .map_err(to_io)
+    }
+
+    fn query_nodes(&self, sql: &str, params: &[&dyn ToSql]) -> rusqlite::Result<Vec<Node>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(sql)?;
+        let mut nodes = vec![];
+        for node in stmt.query_map(params, node_from_row)? {
+            if let Some(node) = skip_bad_row(node)? {
+                nodes.push(node);
+            }
+        }
+        Ok(nodes)
+    }
+}
+
+impl NodeStore for SqliteStore {
+    fn get(&self, addr: &SocketAddr) -> Option<Node> {
+        let sql = format!("SELECT {} FROM nodes WHERE addr = ?1", NODE_COLUMNS);
+        match self.query_nodes(&sql, &[&addr.to_string()]) {
+            Ok(mut nodes) => nodes.pop(),
+            Err(e) => {
+                error!("Couldn't read {} from sqlite: {}", addr, e);
+                None
+            }
+        }
+    }
+    fn nodes(&self) -> Vec<Node> {
+        let sql = format!("SELECT {} FROM nodes", NODE_COLUMNS);
+        self.query_nodes(&sql, &[]).unwrap_or_else(|e| {
+            error!("Couldn't read nodes from sqlite: {}", e);
+            vec![]
+        })
+    }
+    fn init(&mut self, addr: SocketAddr) {
+        let result = self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO nodes (addr, port, state, last_visit, stats)
+             VALUES (?1, ?2, ?3, 0, ?4)",
+            params![
+                addr.to_string(),
+                addr.port(),
+                state_str(&NodeState::Uncontacted),
+                serde_json::to_string(&NodeStats::default()).unwrap(),
+            ],
+        );
+        if let Err(e) = result {
+            error!("Couldn't add {} to sqlite: {}", addr, e);
+        }
+    }
+    fn init_all(&mut self, addrs: &[SocketAddr]) {
+        // one transaction, so one sync for a whole `addr` message
+        let result = (|| -> rusqlite::Result<()> {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT OR IGNORE INTO nodes (addr, port, state, last_visit, stats)
+                     VALUES (?1, ?2, ?3, 0, ?4)",
+                )?;
+                let stats = serde_json::to_string(&NodeStats::default()).unwrap();
+                for addr in addrs {
+                    stmt.execute(params![
+                        addr.to_string(),
+                        addr.port(),
+                        state_str(&NodeState::Uncontacted),
+                        stats,
+                    ])?;
+                }
+            }
+            tx.commit()
+        })();
+        if let Err(e) = result {
+            error!("Couldn't add {} addresses to sqlite: {}", addrs.len(), e);
+        }
+    }
+    fn insert(&mut self, node: Node) {
+        let version = node.version.as_ref();
+        let result = self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO nodes
+             (addr, port, state, last_visit, version, services, user_agent, start_height, stats)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                node.addr.to_string(),
+                node.addr.port(),
+                state_str(&node.state),
+                utils::unix_time(node.last_visit) as i64,
+                version.map(|v| v.version),
+                version.map(|v| v.services as i64),
+                version.map(|v| v.user_agent.clone()),
+                version.map(|v| v.start_height),
+                serde_json::to_string(&node.stats).unwrap(),
+            ],
+        );
+        if let Err(e) = result {
+            error!("Couldn't save {} to sqlite: {}", node.addr, e);
+        }
+    }
+    fn next(&mut self, due: SystemTime, skip: &dyn Fn(&SocketAddr) -> bool) -> Option<Node> {
+        let result = (|| -> rusqlite::Result<Option<Node>> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM nodes WHERE last_visit < ?1 ORDER BY last_visit",
+                NODE_COLUMNS
+            ))?;
+            let mut rows = stmt.query(params![utils::unix_time(due) as i64])?;
+            while let Some(row) = rows.next()? {
+                if let Some(node) = skip_bad_row(node_from_row(row))? {
+                    if !skip(&node.addr) {
+                        return Ok(Some(node));
+                    }
+                }
+            }
+            Ok(None)
+        })();
+        match result {
+            Ok(Some(mut node)) => {
+                node.last_visit = SystemTime::now();
+                self.insert(node.clone());
+                Some(node)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                error!("Couldn't read nodes from sqlite: {}", e);
+                None
+            }
+        }
+    }
+    fn fetch_online_nodes(
+        &self,
+        n: usize,
+        port: u16,
+        skip: &dyn Fn(&SocketAddr) -> bool,
+    ) -> Vec<Node> {
+        let sql = format!(
+            "SELECT {} FROM nodes WHERE state = ?1 AND port = ?2",
+            NODE_COLUMNS
+        );
+        let nodes = self
+            .query_nodes(&sql, &[&state_str(&NodeState::Online), &port])
+            .unwrap_or_else(|e| {
+                error!("Couldn't read nodes from sqlite: {}", e);
+                vec![]
+            });
+        nodes
+            .into_iter()
+            .filter(|node| !skip(&node.addr))
+            .take(n)
+            .collect()
+    }
+    fn report(&self) -> HashMap<NodeState, i32> {
+        let mut report: HashMap<NodeState, i32> = HashMap::new();
+        report.insert(NodeState::Online, 0);
+        report.insert(NodeState::Offline, 0);
+        report.insert(NodeState::Uncontacted, 0);
+        let counts = (|| -> rusqlite::Result<Vec<(String, i32)>> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("SELECT state, COUNT(*) FROM nodes GROUP BY state")?;
+            let rows = stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect()
+        })();
+        match counts {
+            Ok(counts) => {
+                for (state, count) in counts {
+                    report.insert(parse_state(&state), count);
+                }
+            }
+            Err(e) => error!("Couldn't count nodes in sqlite: {}", e),
+        }
+        report
+    }
+    fn record_visit(&mut self, visit: &Visit) {
+        let version = visit.version.as_ref();
+        let result = self.conn.lock().unwrap().execute(
+            "INSERT INTO visits
+             (time, addr, outcome, latency_ms, version, services, start_height)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                utils::unix_time(visit.time) as i64,
+                visit.addr.to_string(),
+                visit.outcome.as_str(),
+                visit.latency.map(|l| l.as_millis() as i64),
+                version.map(|v| v.version),
+                version.map(|v| v.services as i64),
+                version.map(|v| v.start_height),
+            ],
+        );
+        if let Err(e) = result {
+            error!("Couldn't log visit to {}: {}", visit.addr, e);
+        }
+    }
+    // every write is already committed, so all that's left is trimming the log
+    fn flush(&self) -> io::Result<()> {
+        let cutoff = SystemTime::now() - self.retention;
+        self.prune_visits(cutoff)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store(name: &str) -> (SqliteStore, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("seed-{}-{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = SqliteStore::open(&path, Duration::from_secs(3600)).unwrap();
+        (store, path)
+    }
+
+    #[test]
+    fn test_nodes() {
+        let (mut store, path) = temp_store("nodes");
+        let addr: SocketAddr = "1.2.3.4:8333".parse().unwrap();
+        store.init(addr);
+        store.init("5.6.7.8:8334".parse().unwrap());
+        assert_eq!(2, store.report()[&NodeState::Uncontacted]);
+
+        let due = UNIX_EPOCH + Duration::from_secs(1);
+        let mut node = store.next(due, &|a| *a != addr).unwrap();
+        assert_eq!(addr, node.addr);
+        // just visited, no longer due
+        assert!(store.next(due, &|a| *a != addr).is_none());
+
+        node.state = NodeState::Online;
+        node.version = Some(VersionInfo {
+            version: 70015,
+            services: 1033,
+            user_agent: String::from("/Satoshi:0.18.0/"),
+            start_height: 600000,
+        });
+        node.stats.record_visit(true, node.last_visit);
+        node.last_visit = from_unix(utils::unix_time(node.last_visit) as i64);
+        store.insert(node.clone());
+
+        assert_eq!(Some(node.clone()), store.get(&addr));
+        assert_eq!(vec![node], store.fetch_online_nodes(10, 8333, &|_| false));
+        assert!(store
+            .fetch_online_nodes(10, 8333, &|a| *a == addr)
+            .is_empty());
+        assert_eq!(1, store.report()[&NodeState::Online]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_init_all() {
+        let (mut store, path) = temp_store("init-all");
+        let known: SocketAddr = "1.2.3.4:8333".parse().unwrap();
+        store.init(known);
+        let mut node = store.get(&known).unwrap();
+        node.state = NodeState::Online;
+        store.insert(node);
+        let addrs: Vec<SocketAddr> = (0..100)
+            .map(|i| SocketAddr::new([10, 0, 0, i as u8].into(), 8333))
+            .chain(vec![known])
+            .collect();
+        store.init_all(&addrs);
+        assert_eq!(100, store.report()[&NodeState::Uncontacted]);
+        // known addresses are left alone
+        assert_eq!(1, store.report()[&NodeState::Online]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bad_rows_skipped() {
+        let (mut store, path) = temp_store("bad-rows");
+        let addr: SocketAddr = "1.2.3.4:8333".parse().unwrap();
+        store.init(addr);
+        store
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO nodes (addr, port, state, last_visit, stats)
+                 VALUES ('not an address', 8333, 'online', 0, '{}')",
+                NO_PARAMS,
+            )
+            .unwrap();
+        assert_eq!(
+            vec![addr],
+            store.nodes().iter().map(|n| n.addr).collect::<Vec<_>>()
+        );
+        let due = UNIX_EPOCH + Duration::from_secs(1);
+        assert_eq!(addr, store.next(due, &|_| false).unwrap().addr);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_visit_log() {
+        let (mut store, path) = temp_store("visits");
+        let addr: SocketAddr = "1.2.3.4:8333".parse().unwrap();
+        let now = from_unix(utils::unix_time(SystemTime::now()) as i64);
+        let visit = |time, outcome| Visit {
+            time,
+            addr,
+            outcome,
+            latency: Some(Duration::from_millis(40)),
+            version: None,
+        };
+        store.record_visit(&visit(now - Duration::from_secs(7200), VisitOutcome::Error));
+        store.record_visit(&visit(now, VisitOutcome::Offline));
+
+        assert_eq!(2, store.visits(&addr).unwrap().len());
+        // older than the hour of retention
+        store.flush().unwrap();
+        assert_eq!(
+            vec![visit(now, VisitOutcome::Offline)],
+            store.visits(&addr).unwrap()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::process;
+
+// Sockets passed in by systemd (or anything else speaking its LISTEN_FDS
+// protocol), see sd_listen_fds(3). They start at fd 3 and are only meant for
+// us if LISTEN_PID is our pid.
+
+const LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SocketKind {
+    Datagram,
+    Stream,
+}
+
+// the fds described by LISTEN_PID and LISTEN_FDS for the process `pid`
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Result<Vec<RawFd>, io::Error> {
+    let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+    match listen_pid {
+        Some(listen_pid) => {
+            let listen_pid: u32 = listen_pid.parse().map_err(|_| invalid("bad LISTEN_PID"))?;
+            if listen_pid != pid {
+                return Ok(vec![]);
+            }
+        }
+        None => return Ok(vec![]),
+    }
+    let count: RawFd = match listen_fds {
+        Some(count) => count.parse().map_err(|_| invalid("bad LISTEN_FDS"))?,
+        None => return Ok(vec![]),
+    };
+    if count < 0 {
+        return Err(invalid("bad LISTEN_FDS"));
+    }
+    Ok((LISTEN_FDS_START..LISTEN_FDS_START + count).collect())
+}
+
+// take the sockets passed to us, if any; the variables are cleared so this
+// only works once
+pub fn listen_fds() -> Result<Vec<RawFd>, io::Error> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let fds = parse_listen_fds(
+        listen_pid.as_ref().map(|s| s.as_str()),
+        listen_fds.as_ref().map(|s| s.as_str()),
+        process::id(),
+    )?;
+    for fd in &fds {
+        // not to be inherited by anything we might run
+        if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(fds)
+}
+
+pub fn socket_kind(fd: RawFd) -> Result<SocketKind, io::Error> {
+    let mut kind: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    match kind {
+        libc::SOCK_DGRAM => Ok(SocketKind::Datagram),
+        libc::SOCK_STREAM => Ok(SocketKind::Stream),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("fd {} is neither a datagram nor a stream socket", fd),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(
+            vec![3, 4],
+            parse_listen_fds(Some("42"), Some("2"), 42).unwrap()
+        );
+        // meant for another process, e.g. our parent
+        assert!(parse_listen_fds(Some("41"), Some("2"), 42)
+            .unwrap()
+            .is_empty());
+        assert!(parse_listen_fds(None, None, 42).unwrap().is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("two"), 42).is_err());
+    }
+
+    #[test]
+    fn test_socket_kind() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_eq!(SocketKind::Datagram, socket_kind(udp.as_raw_fd()).unwrap());
+        assert_eq!(SocketKind::Stream, socket_kind(tcp.as_raw_fd()).unwrap());
+    }
+}
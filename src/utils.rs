@@ -2,15 +2,21 @@ use bitcoin::network::{
     address::Address, constants::Network, message::NetworkMessage, message_network::VersionMessage,
 };
 use env_logger;
-use log::{info, trace, LevelFilter};
+use log::{info, trace, LevelFilter, Log, Metadata, Record};
 use std::error::Error;
+use std::ffi::OsString;
 use std::fmt;
+use std::fs;
 use std::io;
 use std::io::Write;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use super::resolver::Resolver;
+
 // copied from murmel
 const MAIN_SEEDER: [&str; 5] = [
     "seed.bitcoin.sipa.be",
@@ -25,40 +31,60 @@ const TEST_SEEDER: [&str; 4] = [
     "seed.testnet.bitcoin.sprovoost.nl",
     "testnet-seed.bluematt.me",
 ];
-pub fn dns_seed(network: Network) -> Vec<SocketAddr> {
-    let mut seeds = Vec::new();
-    if network == Network::Bitcoin {
-        info!("reaching out for DNS seed...");
-        for seedhost in MAIN_SEEDER.iter() {
-            match (*seedhost, 8333).to_socket_addrs() {
-                Ok(lookup) => {
-                    for host in lookup {
-                        seeds.push(host);
-                    }
-                }
-                Err(e) => {
-                    trace!("{} did not answer: {:?}", seedhost, e);
-                }
-            }
-        }
-        info!("received {} DNS seeds", seeds.len());
+// the port DNS seed clients will connect to, DNS answers can't carry another
+pub fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8333,
+        Network::Testnet => 18333,
+        Network::Regtest => 18444,
+        // signet, which rust-bitcoin has since added to `Network`
+        _ => 38333,
     }
-    if network == Network::Testnet {
-        info!("reaching out for DNS seed...");
-        for seedhost in TEST_SEEDER.iter() {
-            if let Ok(lookup) = (*seedhost, 18333).to_socket_addrs() {
+}
+
+pub fn dns_seed(network: Network, resolver: &Resolver) -> Vec<SocketAddr> {
+    let mut seeds = Vec::new();
+    let seeders: &[&str] = match network {
+        Network::Bitcoin => &MAIN_SEEDER,
+        Network::Testnet => &TEST_SEEDER,
+        _ => &[],
+    };
+    info!("reaching out for DNS seed...");
+    for seedhost in seeders.iter() {
+        match resolver.resolve(seedhost, default_port(network)) {
+            Ok(lookup) => {
                 for host in lookup {
                     seeds.push(host);
                 }
-            } else {
-                trace!("{} did not answer", seedhost);
+            }
+            Err(e) => {
+                trace!("{} did not answer: {:?}", seedhost, e);
             }
         }
-        info!("received {} DNS seeds", seeds.len());
     }
+    info!("received {} DNS seeds", seeds.len());
     seeds
 }
 
+// write `contents` next to `path` first and rename it into place, so readers
+// never see a half-written file
+pub fn write_atomic(path: &Path, contents: &str) -> Result<(), io::Error> {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".new");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 // copied from murmel
 pub fn compile_version() -> NetworkMessage {
     let timestamp = SystemTime::now()
@@ -81,9 +107,57 @@ pub fn compile_version() -> NetworkMessage {
     })
 }
 
+// env_logger formats whatever it's given, the filtering is ours: RUST_LOG,
+// unless `set_log_level` has overridden it with one level for everything
+struct Logger {
+    inner: env_logger::Logger,
+    filter: env_logger::filter::Filter,
+}
+
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+// an index into `LEVELS`, or `NO_OVERRIDE`
+static LEVEL_OVERRIDE: AtomicUsize = AtomicUsize::new(NO_OVERRIDE);
+const NO_OVERRIDE: usize = usize::max_value();
+// the most RUST_LOG lets through anywhere
+static ENV_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+fn level_override() -> Option<LevelFilter> {
+    LEVELS.get(LEVEL_OVERRIDE.load(Ordering::SeqCst)).cloned()
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match level_override() {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        let wanted = match level_override() {
+            Some(level) => record.level() <= level,
+            None => self.filter.matches(record),
+        };
+        if wanted {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
 pub fn init_logger() {
-    let env = env_logger::Env::default();
-    env_logger::Builder::from_env(env)
+    let inner = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
         .format(|buf, record| {
             writeln!(
                 buf,
@@ -93,7 +167,27 @@ pub fn init_logger() {
                 record.args()
             )
         })
-        .init();
+        .build();
+    let filter = env_logger::filter::Builder::from_env("RUST_LOG").build();
+    ENV_LEVEL.store(filter.filter() as usize, Ordering::SeqCst);
+    log::set_max_level(filter.filter());
+    if log::set_boxed_logger(Box::new(Logger { inner, filter })).is_err() {
+        eprintln!("logger already initialized");
+    }
+}
+
+// log everything at `level` or above, or go back to RUST_LOG with `None`
+pub fn set_log_level(level: Option<LevelFilter>) {
+    match level {
+        Some(level) => {
+            LEVEL_OVERRIDE.store(level as usize, Ordering::SeqCst);
+            log::set_max_level(level);
+        }
+        None => {
+            LEVEL_OVERRIDE.store(NO_OVERRIDE, Ordering::SeqCst);
+            log::set_max_level(LEVELS[ENV_LEVEL.load(Ordering::SeqCst)]);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -129,3 +223,20 @@ impl CrawlerError {
         CrawlerError { msg }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("seed-write-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnsseed.dump");
+        write_atomic(&path, "one").unwrap();
+        write_atomic(&path, "two").unwrap();
+        assert_eq!("two", fs::read_to_string(&path).unwrap());
+        assert!(!dir.join("dnsseed.dump.new").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
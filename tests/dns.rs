@@ -0,0 +1,33 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use bitcoin_seed::dns::{BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType};
+
+#[test]
+fn test_packet_roundtrip() {
+    let mut packet = DnsPacket::new();
+    packet.header.id = 1234;
+    packet.header.response = true;
+    packet
+        .questions
+        .push(DnsQuestion::new(String::from("seed.example.com"), QueryType::A));
+    packet.answers.push(DnsRecord::A {
+        domain: String::from("seed.example.com"),
+        addr: Ipv4Addr::new(1, 2, 3, 4),
+        ttl: 60,
+    });
+    packet.answers.push(DnsRecord::AAAA {
+        domain: String::from("seed.example.com"),
+        addr: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+        ttl: 60,
+    });
+
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    buffer.pos = 0;
+    let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+    assert_eq!(1234, parsed.header.id);
+    assert!(parsed.header.response);
+    assert_eq!(packet.questions, parsed.questions);
+    assert_eq!(packet.answers, parsed.answers);
+}